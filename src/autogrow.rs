@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::core::v1::PersistentVolumeClaim;
+use kube::{
+    api::{Patch, PatchParams},
+    Api,
+};
+use log::{error, info};
+use serde_json::json;
+
+use crate::{
+    config::{CONFIG, NODE},
+    controller::parse_size,
+    statfs::statfs,
+    store::{self, Autogrow, VolumeState, CLIENT},
+};
+
+/// Size to grow a volume to, if it has filled up past its threshold
+async fn target_size(volume: &store::Volume, autogrow: &Autogrow) -> Result<Option<u64>> {
+    if volume.size >= autogrow.max_size {
+        return Ok(None);
+    }
+    // every mount shares the filesystem
+    let Some(path) = volume.mount_paths.first() else {
+        return Ok(None);
+    };
+    let stats = statfs(path).await?;
+    if stats.total_bytes() == 0 {
+        return Ok(None);
+    }
+    let used_percent = stats.used_bytes() * 100 / stats.total_bytes();
+    if used_percent < autogrow.threshold as u64 {
+        return Ok(None);
+    }
+    Ok(Some(
+        volume
+            .size
+            .saturating_add(autogrow.increment)
+            .min(autogrow.max_size),
+    ))
+}
+
+/// Requests `size` on the volume's PVC, so the usual expansion flow grows it
+async fn request_size(volume: &store::Volume, size: u64) -> Result<()> {
+    let (Some(name), Some(namespace)) = (&volume.pvc_name, &volume.pvc_namespace) else {
+        return Err(anyhow!("volume has no recorded PVC"));
+    };
+    let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(CLIENT.clone(), namespace);
+    let pvc = pvcs.get(name).await?;
+    let requested = pvc
+        .spec
+        .and_then(|x| x.resources?.requests?.remove("storage"))
+        .map(|x| parse_size(&x.0).map_err(|e| anyhow!("invalid PVC request: {}", e.message())))
+        .transpose()?
+        .unwrap_or_default();
+    // an earlier request is still being resized
+    if requested >= size {
+        return Ok(());
+    }
+    pvcs.patch(
+        name,
+        &PatchParams::default(),
+        &Patch::Merge(json!({
+            "spec": {
+                "resources": {
+                    "requests": {
+                        "storage": size.to_string(),
+                    },
+                },
+            },
+        })),
+    )
+    .await?;
+    info!(
+        "volume {} filled up, requested {size} bytes on PVC {namespace}/{name}",
+        volume.name
+    );
+    Ok(())
+}
+
+async fn grow_pass() -> Result<()> {
+    for volume in store::Volume::list().await? {
+        let Some(autogrow) = &volume.autogrow else {
+            continue;
+        };
+        if volume.assigned_node_id.as_deref() != Some(&**NODE)
+            || volume.state != VolumeState::NodePublished
+        {
+            continue;
+        }
+        let result = match target_size(&volume, autogrow).await {
+            Ok(Some(size)) => request_size(&volume, size).await,
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("failed to autogrow volume {}: {e:#}", volume.name);
+        }
+    }
+    Ok(())
+}
+
+/// Periodically grows volumes with an autogrow policy that are filling up
+pub async fn run() {
+    if CONFIG.autogrow.interval == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(CONFIG.autogrow.interval);
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = grow_pass().await {
+            error!("autogrow failed: {e:#}");
+        }
+    }
+}
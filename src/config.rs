@@ -1,7 +1,9 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
+use crate::store::Filesystem;
+
 lazy_static::lazy_static! {
     static ref CONFIG_PATH: String = {
         let base = std::env::var("LVP_CONFIG").unwrap_or_default();
@@ -30,4 +32,276 @@ pub struct Config {
     pub socket_path: PathBuf,
     pub database: PathBuf,
     pub host_prefix: PathBuf,
+    /// The host's cgroup v2 hierarchy, mounted into the container for throttling volumes' I/O
+    #[serde(default = "Config::default_cgroup_path")]
+    pub cgroup_path: PathBuf,
+    /// Named storage pools that StorageClasses provision from
+    #[serde(default)]
+    pub pools: BTreeMap<String, Pool>,
+    /// Seconds archived volumes are kept in trash before being purged, forever if unset
+    pub archive_ttl: Option<u64>,
+    #[serde(default)]
+    pub gc: GcConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub autogrow: AutogrowConfig,
+    #[serde(default)]
+    pub trim: TrimConfig,
+    #[serde(default)]
+    pub shrink: ShrinkConfig,
+    /// How host tools such as mkfs are run
+    #[serde(default)]
+    pub executor: Executor,
+    #[serde(default)]
+    pub commands: CommandConfig,
+}
+
+impl Config {
+    fn default_cgroup_path() -> PathBuf {
+        "/host-cgroup".into()
+    }
+}
+
+/// Limits on host tools run by the executor
+#[derive(Serialize, Deserialize)]
+pub struct CommandConfig {
+    /// Seconds a command may run before it is killed
+    #[serde(default = "CommandConfig::default_timeout")]
+    pub timeout: u64,
+    /// Per-program overrides of `timeout`, such as for `mkfs.xfs` on large images
+    #[serde(default)]
+    pub timeouts: BTreeMap<String, u64>,
+}
+
+impl CommandConfig {
+    fn default_timeout() -> u64 {
+        5 * 60
+    }
+
+    pub fn timeout_for(&self, program: &str) -> Duration {
+        self.explicit_timeout(program)
+            .unwrap_or(Duration::from_secs(self.timeout))
+    }
+
+    /// Timeout set for the program itself in `timeouts`
+    pub fn explicit_timeout(&self, program: &str) -> Option<Duration> {
+        self.timeouts.get(program).copied().map(Duration::from_secs)
+    }
+}
+
+impl Default for CommandConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Self::default_timeout(),
+            timeouts: BTreeMap::new(),
+        }
+    }
+}
+
+/// Monitoring of volumes on this node, reported as their `VolumeCondition`
+#[derive(Serialize, Deserialize)]
+pub struct HealthConfig {
+    /// Seconds between checks, disabled if 0
+    #[serde(default = "HealthConfig::default_interval")]
+    pub interval: u64,
+}
+
+impl HealthConfig {
+    fn default_interval() -> u64 {
+        60
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval: Self::default_interval(),
+        }
+    }
+}
+
+/// Growth of volumes with an autogrow policy
+#[derive(Serialize, Deserialize)]
+pub struct AutogrowConfig {
+    /// Seconds between usage checks, disabled if 0
+    #[serde(default = "AutogrowConfig::default_interval")]
+    pub interval: u64,
+}
+
+impl AutogrowConfig {
+    fn default_interval() -> u64 {
+        60
+    }
+}
+
+impl Default for AutogrowConfig {
+    fn default() -> Self {
+        Self {
+            interval: Self::default_interval(),
+        }
+    }
+}
+
+/// Shrinking of unpublished volumes annotated with `lvp/shrink-to`
+#[derive(Serialize, Deserialize)]
+pub struct ShrinkConfig {
+    /// Seconds between checks for requested sizes, disabled if 0
+    #[serde(default = "ShrinkConfig::default_interval")]
+    pub interval: u64,
+}
+
+impl ShrinkConfig {
+    fn default_interval() -> u64 {
+        5 * 60
+    }
+}
+
+impl Default for ShrinkConfig {
+    fn default() -> Self {
+        Self {
+            interval: Self::default_interval(),
+        }
+    }
+}
+
+/// Discarding of unused blocks in mounted `ext4` and `xfs` volumes, which punches holes in their images
+#[derive(Serialize, Deserialize)]
+pub struct TrimConfig {
+    /// Seconds between trims, disabled if 0
+    #[serde(default = "TrimConfig::default_interval")]
+    pub interval: u64,
+    /// Trim volumes before their last unmount
+    #[serde(default = "TrimConfig::default_on_unpublish")]
+    pub on_unpublish: bool,
+}
+
+impl TrimConfig {
+    fn default_interval() -> u64 {
+        24 * 60 * 60
+    }
+
+    fn default_on_unpublish() -> bool {
+        true
+    }
+}
+
+impl Default for TrimConfig {
+    fn default() -> Self {
+        Self {
+            interval: Self::default_interval(),
+            on_unpublish: Self::default_on_unpublish(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Executor {
+    /// Join the host's mount namespace, requires `hostPID`
+    HostNamespace,
+    /// Bind mount directories of this container into a chroot
+    Chroot {
+        #[serde(default = "Executor::default_chroot_path")]
+        path: PathBuf,
+        /// Directories bound into the chroot, relative to `/`
+        #[serde(default = "Executor::default_chroot_binds")]
+        binds: Vec<String>,
+    },
+}
+
+impl Executor {
+    pub fn default_chroot_path() -> PathBuf {
+        PathBuf::from("/chr")
+    }
+
+    fn default_chroot_binds() -> Vec<String> {
+        [
+            "lib", "lib64", "bin", "sbin", "proc", "usr", "host", "var", "etc",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect()
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::Chroot {
+            path: Self::default_chroot_path(),
+            binds: Self::default_chroot_binds(),
+        }
+    }
+}
+
+/// Periodic reconciliation of the store against pools and the cluster
+#[derive(Serialize, Deserialize)]
+pub struct GcConfig {
+    /// Seconds between passes, disabled if 0
+    #[serde(default = "GcConfig::default_interval")]
+    pub interval: u64,
+    /// Remove orphans instead of only reporting them
+    #[serde(default)]
+    pub clean: bool,
+    /// Seconds an orphan has to be seen for before it is cleaned
+    #[serde(default = "GcConfig::default_grace_period")]
+    pub grace_period: u64,
+}
+
+impl GcConfig {
+    fn default_interval() -> u64 {
+        60 * 60
+    }
+
+    fn default_grace_period() -> u64 {
+        24 * 60 * 60
+    }
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            interval: Self::default_interval(),
+            clean: false,
+            grace_period: Self::default_grace_period(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Pool {
+    /// Directory volumes are created in, relative to `host_prefix`. Unused for memory pools.
+    #[serde(default)]
+    pub path: String,
+    #[serde(default)]
+    pub backend: PoolBackend,
+    /// Filesystems allowed in this pool, any supported by the backend if empty
+    #[serde(default)]
+    pub filesystems: Vec<Filesystem>,
+    /// Smallest single volume allowed, in bytes
+    pub min_volume_size: Option<u64>,
+    /// Largest single volume allowed, in bytes
+    pub max_volume_size: Option<u64>,
+    /// Total bytes that may be provisioned from this pool
+    pub capacity: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolBackend {
+    /// Image files and directories on a host filesystem
+    #[default]
+    Disk,
+    /// tmpfs volumes backed by node memory
+    Memory,
+}
+
+impl Pool {
+    pub fn allows(&self, filesystem: Filesystem) -> bool {
+        let backend_allows = match self.backend {
+            PoolBackend::Disk => filesystem != Filesystem::Tmpfs,
+            PoolBackend::Memory => filesystem == Filesystem::Tmpfs,
+        };
+        backend_allows && (self.filesystems.is_empty() || self.filesystems.contains(&filesystem))
+    }
 }
@@ -1,10 +1,11 @@
-use std::path::Path;
+use std::{collections::HashMap, io::ErrorKind};
 
 use log::{error, info};
 use tonic::{Request, Response, Status};
 
 use crate::{
-    config::{CONFIG, NODE},
+    config::{Pool, PoolBackend, CONFIG, NODE},
+    paths::{self, VolumePath},
     proto::{
         controller_server::Controller,
         controller_service_capability::rpc::Type as RpcType,
@@ -14,25 +15,777 @@ use crate::{
         volume_capability::{access_mode::Mode, *},
         *,
     },
-    store::{self, Filesystem, VolumeConfig, VolumeCreation, VolumeMode, VolumeState},
+    shrink,
+    store::{
+        self, Autogrow, Condition, DeletePolicy, Filesystem, FsckPolicy, IoLimits, VolumeConfig,
+        VolumeCreation, VolumeMode, VolumeState, WipeMode,
+    },
+    trash, wipe,
 };
 
 #[derive(Debug)]
 pub struct ControllerService {}
 
-fn parse_filesystem(from: &str) -> Result<Filesystem, Status> {
+const PVC_NAME_PARAMETER: &str = "csi.storage.k8s.io/pvc/name";
+const PVC_NAMESPACE_PARAMETER: &str = "csi.storage.k8s.io/pvc/namespace";
+const PV_NAME_PARAMETER: &str = "csi.storage.k8s.io/pv/name";
+/// StorageClass parameters `create_volume` accepts, which other RPCs given them should let through
+const VOLUME_PARAMETERS: &[&str] = &[
+    "pool",
+    "host_base_path",
+    "path_pattern",
+    "fs_type",
+    "delete_policy",
+    "wipe_on_delete",
+    "fsck",
+    "allow_shrink",
+    "autogrow_threshold",
+    "autogrow_increment",
+    "autogrow_max_size",
+    "direct_io",
+    "logical_block_size",
+    "read_iops",
+    "write_iops",
+    "read_bps",
+    "write_bps",
+    "uid",
+    "gid",
+    "mode",
+    PVC_NAME_PARAMETER,
+    PVC_NAMESPACE_PARAMETER,
+    PV_NAME_PARAMETER,
+];
+
+fn volume_context(volume: &store::Volume) -> HashMap<String, String> {
+    [
+        (PVC_NAME_PARAMETER, &volume.pvc_name),
+        (PVC_NAMESPACE_PARAMETER, &volume.pvc_namespace),
+        (PV_NAME_PARAMETER, &volume.pv_name),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some((key.to_string(), value.clone()?)))
+    .collect()
+}
+
+/// A volume's condition as reported to the CO, normal unless a problem was recorded
+pub fn volume_condition(condition: Option<Condition>) -> VolumeCondition {
+    match condition {
+        Some(x) => VolumeCondition {
+            abnormal: x.abnormal,
+            message: x.message,
+        },
+        None => VolumeCondition {
+            abnormal: false,
+            message: String::new(),
+        },
+    }
+}
+
+pub fn parse_filesystem(from: &str) -> Result<Filesystem, Status> {
     match from {
         "ext4" => Ok(Filesystem::Ext4),
         "xfs" => Ok(Filesystem::Xfs),
         "bind" => Ok(Filesystem::Bind),
+        "tmpfs" => Ok(Filesystem::Tmpfs),
         _ => {
             return Err(Status::invalid_argument(
-                "unknown fs_type, only 'ext4', 'xfs', or 'bind' allowed",
+                "unknown fs_type, only 'ext4', 'xfs', 'bind', or 'tmpfs' allowed",
             ))
         }
     }
 }
 
+/// Parses a K8S quantity such as `512Mi`, `1.5Gi`, `10G` or `1e9` into bytes, rounding fractions of a
+/// byte up as K8S does. Negative quantities have no size.
+pub fn parse_quantity(from: &str) -> Option<u64> {
+    let from = from.strip_prefix('+').unwrap_or(from);
+    let split = from
+        .find(|x: char| !x.is_ascii_digit() && x != '.')
+        .unwrap_or(from.len());
+    let (number, suffix) = from.split_at(split);
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if whole.is_empty() && fraction.is_empty() || fraction.contains('.') {
+        return None;
+    }
+    let digits = format!("{whole}{fraction}");
+    // u128 holds any 38 digits
+    let digits = digits.trim_start_matches('0');
+    if digits.len() > 38 {
+        return None;
+    }
+    let mantissa: u128 = if digits.is_empty() {
+        0
+    } else {
+        digits.parse().ok()?
+    };
+
+    let (binary, decimal): (u32, i32) = match suffix {
+        "Ki" => (1, 0),
+        "Mi" => (2, 0),
+        "Gi" => (3, 0),
+        "Ti" => (4, 0),
+        "Pi" => (5, 0),
+        "Ei" => (6, 0),
+        "n" => (0, -9),
+        "u" => (0, -6),
+        "m" => (0, -3),
+        "" => (0, 0),
+        "k" => (0, 3),
+        "M" => (0, 6),
+        "G" => (0, 9),
+        "T" => (0, 12),
+        "P" => (0, 15),
+        "E" => (0, 18),
+        _ => {
+            let exponent = suffix.strip_prefix(['e', 'E'])?;
+            let exponent = exponent.strip_prefix('+').unwrap_or(exponent);
+            (0, exponent.parse().ok()?)
+        }
+    };
+    let value = mantissa.checked_mul(1024u128.checked_pow(binary)?)?;
+    let exponent = decimal.checked_sub(fraction.len().try_into().ok()?)?;
+    let value = if exponent >= 0 {
+        value.checked_mul(10u128.checked_pow(exponent as u32)?)?
+    } else {
+        match 10u128.checked_pow(exponent.unsigned_abs()) {
+            Some(divisor) => value.div_ceil(divisor),
+            // smaller than any byte
+            None => (value > 0) as u128,
+        }
+    };
+    value.try_into().ok()
+}
+
+/// Parses a size given either in bytes or as a K8S quantity (`512Mi`, `1.5Gi`, `10G`, ...)
+pub fn parse_size(from: &str) -> Result<u64, Status> {
+    parse_quantity(from).ok_or_else(|| Status::invalid_argument(format!("invalid size '{from}'")))
+}
+
+/// Renders a `path_pattern` parameter such as `${pvc.namespace}/${pvc.name}` into a relative path.
+/// Substituted values may only contain `[A-Za-z0-9._-]` so they can never introduce extra components.
+fn render_path_pattern(
+    pattern: &str,
+    volume_name: &str,
+    pvc_name: Option<&str>,
+    pvc_namespace: Option<&str>,
+    pv_name: Option<&str>,
+) -> Result<String, Status> {
+    let mut out = String::new();
+    let mut rest = pattern;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            return Err(Status::invalid_argument(
+                "unterminated placeholder in path_pattern",
+            ));
+        };
+        let placeholder = &rest[start + 2..start + end];
+        let value = match placeholder {
+            "volume.name" => Some(volume_name),
+            "pvc.name" => pvc_name,
+            "pvc.namespace" => pvc_namespace,
+            "pv.name" => pv_name,
+            _ => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown placeholder '{placeholder}' in path_pattern"
+                )))
+            }
+        };
+        let Some(value) = value else {
+            return Err(Status::invalid_argument(format!(
+                "path_pattern uses '{placeholder}', but it wasn't provided (is csi-provisioner running with --extra-create-metadata?)"
+            )));
+        };
+        if value.is_empty()
+            || value == "."
+            || value == ".."
+            || !value
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || x == '.' || x == '_' || x == '-')
+        {
+            return Err(Status::invalid_argument(format!(
+                "value of '{placeholder}' is not allowed in a path"
+            )));
+        }
+        out.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+
+    if out.is_empty()
+        || out.starts_with('/')
+        || out.contains(['$', '{', '}', '\\'])
+        || paths::normalize(&out).is_err()
+    {
+        return Err(Status::invalid_argument(format!(
+            "path_pattern rendered to invalid path '{out}'"
+        )));
+    }
+    Ok(out)
+}
+
+/// Makes sure no other volume (or stray file) already occupies `host_path`, or a parent or child of it
+async fn check_host_path_collision(
+    name: &str,
+    host_path: &str,
+    pool: Option<&str>,
+) -> Result<(), Status> {
+    let volumes = store::Volume::list().await.map_err(|e| {
+        error!("failed to list volumes: {e:#}");
+        Status::internal("failed to list volumes")
+    })?;
+    // retried creation, reconciled against the existing volume
+    if volumes.iter().any(|x| x.name == name) {
+        return Ok(());
+    }
+    let overlaps = |other: &str| {
+        !other.is_empty()
+            && (other == host_path
+                || other.starts_with(&format!("{host_path}/"))
+                || host_path.starts_with(&format!("{other}/")))
+    };
+    if let Some(other) = volumes.iter().find(|x| overlaps(&x.host_path)) {
+        return Err(Status::already_exists(format!(
+            "host path '{host_path}' collides with volume {}",
+            other.name
+        )));
+    }
+    // volumes still being created only show up through their reservations
+    let reservations = store::PathReservation::list().await.map_err(|e| {
+        error!("failed to list path reservations: {e:#}");
+        Status::internal("failed to list path reservations")
+    })?;
+    if let Some(other) = reservations
+        .iter()
+        .find(|x| x.volume != name && overlaps(&x.host_path))
+    {
+        return Err(Status::already_exists(format!(
+            "host path '{host_path}' collides with volume {}",
+            other.volume
+        )));
+    }
+    let volume_path = VolumePath::new(host_path, pool)
+        .map_err(|e| Status::invalid_argument(format!("invalid host path: {e}")))?;
+    // anything unexpected in the way, like a symlink, counts as a collision too
+    if volume_path.exists().await.unwrap_or(true) {
+        return Err(Status::already_exists(format!(
+            "host path '{host_path}' already exists"
+        )));
+    }
+    Ok(())
+}
+
+/// Reserves `host_path` for the volume, failing if another volume has it or a path overlapping it
+async fn reserve_host_path(
+    reservation: &store::PathReservation,
+    pool: Option<&str>,
+) -> Result<(), Status> {
+    let reserved = reservation.create().await.map_err(|e| {
+        error!("failed to reserve host path: {e:#}");
+        Status::internal("failed to reserve host path")
+    })?;
+    if !reserved {
+        return Err(Status::already_exists(format!(
+            "host path '{}' is reserved by another volume",
+            reservation.host_path
+        )));
+    }
+    // checked once reserved, so of two volumes racing for overlapping paths at least one sees the other
+    let checked =
+        check_host_path_collision(&reservation.volume, &reservation.host_path, pool).await;
+    if checked.is_err() {
+        if let Err(e) = reservation.delete().await {
+            error!(
+                "failed to release host path '{}': {e:#}",
+                reservation.host_path
+            );
+        }
+    }
+    checked
+}
+
+/// Drops a `path_pattern` reservation, along with the directories the pattern made once the data
+/// beneath them is gone
+pub async fn release_host_path(
+    reservation: &store::PathReservation,
+    data_removed: bool,
+) -> anyhow::Result<()> {
+    if data_removed {
+        let depth = paths::normalize(&reservation.host_path)?
+            .components()
+            .count()
+            .saturating_sub(
+                paths::normalize(&reservation.base_path)?
+                    .components()
+                    .count()
+                    + 1,
+            );
+        VolumePath::new(&reservation.host_path, reservation.pool.as_deref())?
+            .remove_empty_parents(depth)
+            .await?;
+    }
+    reservation.delete().await
+}
+
+/// Resolves the pool a volume is provisioned from, either by name or from a legacy `host_base_path`,
+/// which has to lie within one of the configured pools
+pub fn resolve_pool(
+    pool: Option<&str>,
+    host_base_path: Option<&str>,
+) -> Result<Option<(&'static String, &'static Pool)>, Status> {
+    match (pool, host_base_path) {
+        (Some(_), Some(_)) => Err(Status::invalid_argument(
+            "only one of pool or host_base_path may be specified",
+        )),
+        (Some(pool), None) => match CONFIG.pools.get_key_value(pool) {
+            Some(pool) => Ok(Some(pool)),
+            None => Err(Status::invalid_argument(format!("unknown pool {pool}"))),
+        },
+        (None, Some(host_base_path)) => {
+            let host_base_path = paths::normalize(host_base_path)
+                .map_err(|_| Status::invalid_argument("invalid host_base_path"))?;
+            CONFIG
+                .pools
+                .iter()
+                .find(|(_, pool)| {
+                    pool.backend == PoolBackend::Disk
+                        && paths::normalize(&pool.path)
+                            .map(|x| host_base_path.starts_with(x))
+                            .unwrap_or_default()
+                })
+                .map(Some)
+                .ok_or_else(|| {
+                    Status::invalid_argument("host_base_path is outside of all configured pools")
+                })
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Total size of the volumes provisioned from a pool, excluding `except`
+async fn pool_usage(pool: &str, except: &str) -> Result<u64, Status> {
+    let volumes = store::Volume::list().await.map_err(|e| {
+        error!("failed to list volumes: {e:#}");
+        Status::internal("failed to list volumes")
+    })?;
+    Ok(volumes
+        .iter()
+        .filter(|x| x.pool.as_deref() == Some(pool) && x.name != except)
+        .map(|x| x.size)
+        .sum())
+}
+
+/// Validates a new volume against the limits of its pool
+pub async fn check_pool(
+    name: &str,
+    pool: &Pool,
+    filesystem: Filesystem,
+    size: u64,
+    volume_name: &str,
+) -> Result<(), Status> {
+    if !pool.allows(filesystem) {
+        return Err(Status::invalid_argument(format!(
+            "fs_type {filesystem:?} is not allowed in pool {name}"
+        )));
+    }
+    if pool.min_volume_size.map(|x| size < x).unwrap_or_default()
+        || pool.max_volume_size.map(|x| size > x).unwrap_or_default()
+    {
+        return Err(Status::out_of_range(format!(
+            "volume size is outside the limits of pool {name}"
+        )));
+    }
+    if let Some(capacity) = pool.capacity {
+        if pool_usage(name, volume_name).await? + size > capacity {
+            return Err(Status::resource_exhausted(format!(
+                "pool {name} is out of capacity"
+            )));
+        }
+    }
+    Ok(())
+}
+
+async fn memory_available() -> std::io::Result<u64> {
+    let meminfo = tokio::fs::read_to_string("/proc/meminfo").await?;
+    meminfo
+        .lines()
+        .find_map(|x| x.strip_prefix("MemAvailable:"))
+        .and_then(|x| x.trim().strip_suffix("kB")?.trim().parse::<u64>().ok())
+        .map(|x| x * 1024)
+        .ok_or_else(|| std::io::Error::other("missing MemAvailable"))
+}
+
+/// Builds the path of a volume relative to `host_prefix`
+pub fn volume_host_path(
+    host_base_path: Option<&str>,
+    name: &str,
+    filesystem: Filesystem,
+) -> Result<String, Status> {
+    // tmpfs volumes live in memory, so there is nothing to place on the host
+    if filesystem == Filesystem::Tmpfs {
+        return Ok(String::new());
+    }
+    let Some(host_base_path) = host_base_path else {
+        return Err(Status::invalid_argument("missing host_base_path"));
+    };
+
+    let host_base_path = paths::normalize(host_base_path)
+        .map_err(|_| Status::invalid_argument("invalid host_base_path"))?;
+    let name = match paths::normalize(name) {
+        Ok(name) if !name.as_os_str().is_empty() => name,
+        _ => return Err(Status::invalid_argument("invalid volume name")),
+    };
+    let host_path = host_base_path.join(name);
+    if host_path.iter().any(|x| x == paths::TRASH_DIR) {
+        return Err(Status::invalid_argument(format!(
+            "volume paths may not contain '{}'",
+            paths::TRASH_DIR
+        )));
+    }
+    Ok(host_path.display().to_string())
+}
+
+/// A statically provisioned volume that lvp did not create, described by its PV volume attributes
+pub struct StaticVolume {
+    pub host_path: String,
+    pub filesystem: Filesystem,
+    pub size: Option<u64>,
+    pub adopt: bool,
+    /// What deleting an adopted volume does to its data, retained unless the PV asks for more
+    pub delete_policy: DeletePolicy,
+}
+
+/// Parses the volume attributes of a static PV, or `None` if it isn't one
+pub fn parse_static_volume(
+    volume_context: &HashMap<String, String>,
+    requested_filesystem: Option<Filesystem>,
+) -> Result<Option<StaticVolume>, Status> {
+    if !volume_context.contains_key("host_path") {
+        return Ok(None);
+    }
+    let mut host_path = "";
+    let mut filesystem = requested_filesystem;
+    let mut size = None::<u64>;
+    let mut adopt = false;
+    let mut delete_policy = DeletePolicy::Retain;
+    for (name, value) in volume_context {
+        match &**name {
+            "host_path" => host_path = value,
+            "fs_type" => {
+                let fs = parse_filesystem(value)?;
+                if filesystem.is_some() && filesystem != Some(fs) {
+                    return Err(Status::invalid_argument(
+                        "conflicting filesystems specified",
+                    ));
+                }
+                filesystem = Some(fs);
+            }
+            "size" => size = Some(parse_size(value)?),
+            "adopt" => adopt = value == "true",
+            "delete_policy" => delete_policy = parse_delete_policy(value)?,
+            // pod info passed along by kubelet
+            _ if name.starts_with("csi.storage.k8s.io/") => (),
+            _ => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown volume attribute {name}"
+                )))
+            }
+        }
+    }
+    let filesystem = filesystem.unwrap_or_default();
+    if filesystem == Filesystem::Tmpfs {
+        return Err(Status::invalid_argument(
+            "tmpfs volumes cannot be statically provisioned",
+        ));
+    }
+    let host_path = match paths::normalize(host_path) {
+        Ok(host_path) if !host_path.as_os_str().is_empty() => host_path,
+        _ => return Err(Status::invalid_argument("invalid host_path")),
+    };
+    Ok(Some(StaticVolume {
+        host_path: host_path.display().to_string(),
+        filesystem,
+        size,
+        adopt,
+        delete_policy,
+    }))
+}
+
+/// Registers a static volume in the store so that it becomes fully managed by lvp
+pub async fn adopt_volume(
+    volume_id: &str,
+    node_id: &str,
+    static_volume: &StaticVolume,
+    config: VolumeConfig,
+    state: VolumeState,
+) -> Result<store::Volume, Status> {
+    let volume_path = VolumePath::new(&static_volume.host_path, None)
+        .map_err(|e| Status::invalid_argument(format!("invalid host_path: {e}")))?;
+    let total_path = volume_path.path();
+    let metadata = match volume_path
+        .open(libc::O_PATH)
+        .await
+        .and_then(|x| x.metadata())
+    {
+        Ok(x) => x,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(Status::not_found("host_path not found"))
+        }
+        Err(e) => {
+            error!(
+                "failed to stat static volume '{}': {e}",
+                total_path.display()
+            );
+            return Err(Status::invalid_argument(format!("invalid host_path: {e}")));
+        }
+    };
+    if metadata.is_dir() != (static_volume.filesystem == Filesystem::Bind) {
+        return Err(Status::invalid_argument(
+            "host_path does not match fs_type, bind volumes must be directories and others image files",
+        ));
+    }
+    let size = match static_volume.size {
+        Some(size) => size,
+        None if static_volume.filesystem == Filesystem::Bind => 0,
+        None => metadata.len(),
+    };
+
+    info!(
+        "adopting static volume {volume_id} @ '{}'",
+        total_path.display()
+    );
+    let volume = store::Volume {
+        name: volume_id.to_string(),
+        size,
+        assigned_node_id: Some(node_id.to_string()),
+        state,
+        published_readonly: false,
+        published_config: if state == VolumeState::Open {
+            None
+        } else {
+            Some(config.clone())
+        },
+        host_path: static_volume.host_path.clone(),
+        pool: None,
+        filesystem: static_volume.filesystem,
+        valid_configs: vec![config],
+        loop_device: None,
+        mount_paths: vec![],
+        ephemeral: false,
+        pvc_name: None,
+        pvc_namespace: None,
+        pv_name: None,
+        // the data predates lvp, so only a PV asking for it gets it deleted
+        delete_policy: static_volume.delete_policy,
+        wipe_on_delete: Default::default(),
+        wipe_offset: 0,
+        fsck: Default::default(),
+        condition: None,
+        needs_fs_resize: false,
+        allow_shrink: false,
+        refused_shrink: None,
+        autogrow: None,
+        direct_io: false,
+        logical_block_size: None,
+        io_limits: None,
+        throttled_device: None,
+        uid: None,
+        gid: None,
+        mode: None,
+        adopted: true,
+    };
+    let creation = volume.create().await.map_err(|e| {
+        error!("failed to save adopted volume: {e:#}");
+        Status::internal("internal failure")
+    })?;
+    match creation {
+        VolumeCreation::Success => Ok(volume),
+        VolumeCreation::AlreadyExists => match store::Volume::load(volume_id).await {
+            Ok(Some(x)) => Ok(x),
+            // race conditioned
+            Ok(None) => Err(Status::aborted("adopted volume concurrently deleted")),
+            Err(e) => {
+                error!("failed to load adopted volume: {e:#}");
+                Err(Status::internal("internal failure"))
+            }
+        },
+    }
+}
+
+pub fn parse_wipe_mode(from: &str) -> Result<WipeMode, Status> {
+    match from {
+        "none" => Ok(WipeMode::None),
+        "discard" => Ok(WipeMode::Discard),
+        "zero" => Ok(WipeMode::Zero),
+        _ => Err(Status::invalid_argument(
+            "unknown wipe_on_delete, only 'none', 'discard', or 'zero' allowed",
+        )),
+    }
+}
+
+pub fn parse_fsck_policy(from: &str) -> Result<FsckPolicy, Status> {
+    match from {
+        "none" => Ok(FsckPolicy::None),
+        "check" => Ok(FsckPolicy::Check),
+        "repair" => Ok(FsckPolicy::Repair),
+        _ => Err(Status::invalid_argument(
+            "unknown fsck, only 'none', 'check', or 'repair' allowed",
+        )),
+    }
+}
+
+/// Parses a numeric user or group id
+pub fn parse_id(name: &str, from: &str) -> Result<u32, Status> {
+    from.parse()
+        .map_err(|_| Status::invalid_argument(format!("{name} must be a numeric id, not '{from}'")))
+}
+
+/// Parses permissions in octal, such as `0775` or `2770`
+fn parse_mode(from: &str) -> Result<u32, Status> {
+    u32::from_str_radix(from, 8)
+        .ok()
+        .filter(|x| *x <= 0o7777)
+        .ok_or_else(|| {
+            Status::invalid_argument(format!("invalid mode '{from}', expected octal permissions"))
+        })
+}
+
+/// Parses a positive `io.max` limit, in bytes (as a size) for bandwidth or a plain count for IOPS
+fn parse_io_limit(name: &str, from: &str, bytes: bool) -> Result<u64, Status> {
+    let limit = if bytes {
+        parse_size(from)?
+    } else {
+        from.parse()
+            .map_err(|_| Status::invalid_argument(format!("invalid {name} '{from}'")))?
+    };
+    if limit == 0 {
+        return Err(Status::invalid_argument(format!("{name} must be positive")));
+    }
+    Ok(limit)
+}
+
+pub fn parse_delete_policy(from: &str) -> Result<DeletePolicy, Status> {
+    match from {
+        "delete" => Ok(DeletePolicy::Delete),
+        "archive" => Ok(DeletePolicy::Archive),
+        "retain" => Ok(DeletePolicy::Retain),
+        _ => Err(Status::invalid_argument(
+            "unknown delete_policy, only 'delete', 'archive', or 'retain' allowed",
+        )),
+    }
+}
+
+/// Moves the data backing a volume into the trash directory of its pool
+pub async fn archive_volume_data(volume: &store::Volume) -> std::io::Result<()> {
+    // nothing persisted on the host
+    if volume.filesystem == Filesystem::Tmpfs {
+        return Ok(());
+    }
+    match VolumePath::of(volume)?
+        .archive(&trash::archive_name(&volume.name))
+        .await
+    {
+        Ok(path) => {
+            info!("archived volume {} to {}", volume.name, path.display());
+            Ok(())
+        }
+        // never created, volumes are only made on first publish
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes the data backing a volume from the host
+pub async fn remove_volume_data(volume: &store::Volume) -> std::io::Result<()> {
+    // nothing persisted on the host
+    if volume.filesystem == Filesystem::Tmpfs {
+        return Ok(());
+    }
+    match VolumePath::of(volume)?.remove().await {
+        // never created, volumes are only made on first publish
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        x => x,
+    }
+}
+
+/// Deletes a volume that is no longer in use, handling its data according to its delete policy
+pub async fn destroy_volume(mut volume: store::Volume) -> Result<(), Status> {
+    let _shrink_hold =
+        shrink::hold(&volume.name).ok_or_else(|| Status::unavailable("volume is being shrunk"))?;
+    if !matches!(volume.state, VolumeState::Open | VolumeState::Wiping) {
+        return Err(Status::failed_precondition(format!(
+            "volume is in use, state = {:?}",
+            volume.state
+        )));
+    }
+    if volume.wipe_on_delete != WipeMode::None && volume.delete_policy == DeletePolicy::Delete {
+        if volume.state != VolumeState::Wiping {
+            volume.state = VolumeState::Wiping;
+            volume.wipe_offset = 0;
+            volume.update().await.map_err(|e| {
+                error!("failed to update volume: {e:#}");
+                Status::internal("failed to update volume")
+            })?;
+        }
+        wipe::wipe_volume_data(&mut volume).await.map_err(|e| {
+            error!("failed to wipe volume {}: {e:#}", volume.name);
+            Status::internal("failed to wipe volume")
+        })?;
+    }
+    match volume.delete_policy {
+        DeletePolicy::Delete => remove_volume_data(&volume).await.map_err(|e| {
+            error!("failed to delete volume file: {e:#}");
+            Status::internal("internal failure")
+        })?,
+        DeletePolicy::Archive => archive_volume_data(&volume).await.map_err(|e| {
+            error!("failed to archive volume file: {e:#}");
+            Status::internal("internal failure")
+        })?,
+        DeletePolicy::Retain if volume.filesystem == Filesystem::Tmpfs => (),
+        DeletePolicy::Retain => {
+            info!(
+                "retaining data of volume {} @ '{}'",
+                volume.name, volume.host_path
+            );
+            store::Retained {
+                name: volume.name.clone(),
+                host_path: volume.host_path.clone(),
+                assigned_node_id: volume.assigned_node_id.clone(),
+            }
+            .create()
+            .await
+            .map_err(|e| {
+                error!("failed to record retained volume: {e:#}");
+                Status::internal("internal failure")
+            })?;
+        }
+    }
+    let reservation = store::PathReservation::load(&volume.host_path)
+        .await
+        .map_err(|e| {
+            error!("failed to load path reservation: {e:#}");
+            Status::internal("internal failure")
+        })?;
+    if let Some(reservation) = reservation.filter(|x| x.volume == volume.name) {
+        let data_removed = volume.delete_policy != DeletePolicy::Retain;
+        release_host_path(&reservation, data_removed)
+            .await
+            .map_err(|e| {
+                error!(
+                    "failed to release host path of volume {}: {e:#}",
+                    volume.name
+                );
+                Status::internal("internal failure")
+            })?;
+    }
+
+    volume.delete().await.map_err(|e| {
+        error!("failed to delete volume: {e:#}");
+        Status::internal("internal failure")
+    })?;
+    Ok(())
+}
+
 pub fn parse_volume_capability(
     capability: &VolumeCapability,
 ) -> Result<(VolumeConfig, Option<Filesystem>), Status> {
@@ -97,12 +850,73 @@ impl Controller for ControllerService {
             return Err(Status::invalid_argument("no capabilities specified"));
         }
 
+        let mut pool = None::<String>;
         let mut host_base_path = None::<String>;
+        let mut path_pattern = None::<String>;
         let mut filesystem = None::<Filesystem>;
+        let mut pvc_name = None::<String>;
+        let mut pvc_namespace = None::<String>;
+        let mut pv_name = None::<String>;
+        let mut delete_policy = DeletePolicy::default();
+        let mut wipe_on_delete = WipeMode::default();
+        let mut fsck = FsckPolicy::default();
+        let mut allow_shrink = false;
+        let mut autogrow_threshold = None::<u8>;
+        let mut autogrow_increment = 1073741824; // 1 GiB
+        let mut autogrow_max_size = None::<u64>;
+        let mut direct_io = false;
+        let mut logical_block_size = None::<u32>;
+        let mut io_limits = IoLimits::default();
+        let (mut uid, mut gid, mut mode) = (None, None, None);
         for (name, value) in &request.parameters {
             match &**name {
+                "pool" => pool = Some(value.clone()),
                 "host_base_path" => host_base_path = Some(value.clone()),
+                "path_pattern" => path_pattern = Some(value.clone()),
                 "fs_type" => filesystem = Some(parse_filesystem(value)?),
+                "delete_policy" => delete_policy = parse_delete_policy(value)?,
+                "wipe_on_delete" => wipe_on_delete = parse_wipe_mode(value)?,
+                "fsck" => fsck = parse_fsck_policy(value)?,
+                "allow_shrink" => allow_shrink = value == "true",
+                "autogrow_threshold" => {
+                    autogrow_threshold = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|x| (1..100).contains(x))
+                            .ok_or_else(|| {
+                                Status::invalid_argument(
+                                    "autogrow_threshold must be a percentage between 1 and 99",
+                                )
+                            })?,
+                    )
+                }
+                "autogrow_increment" => autogrow_increment = parse_size(value)?,
+                "autogrow_max_size" => autogrow_max_size = Some(parse_size(value)?),
+                "direct_io" => direct_io = value == "true",
+                "logical_block_size" => {
+                    logical_block_size = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|x| matches!(x, 512 | 1024 | 2048 | 4096))
+                            .ok_or_else(|| {
+                                Status::invalid_argument(
+                                    "logical_block_size must be one of 512, 1024, 2048 or 4096",
+                                )
+                            })?,
+                    )
+                }
+                "read_iops" => io_limits.read_iops = Some(parse_io_limit(name, value, false)?),
+                "write_iops" => io_limits.write_iops = Some(parse_io_limit(name, value, false)?),
+                "read_bps" => io_limits.read_bps = Some(parse_io_limit(name, value, true)?),
+                "write_bps" => io_limits.write_bps = Some(parse_io_limit(name, value, true)?),
+                "uid" => uid = Some(parse_id(name, value)?),
+                "gid" => gid = Some(parse_id(name, value)?),
+                "mode" => mode = Some(parse_mode(value)?),
+                PVC_NAME_PARAMETER => pvc_name = Some(value.clone()),
+                PVC_NAMESPACE_PARAMETER => pvc_namespace = Some(value.clone()),
+                PV_NAME_PARAMETER => pv_name = Some(value.clone()),
                 _ => {
                     return Err(Status::invalid_argument(format!(
                         "unknown parameter {name}"
@@ -110,10 +924,6 @@ impl Controller for ControllerService {
                 }
             }
         }
-        let Some(host_base_path) = host_base_path else {
-            return Err(Status::invalid_argument(format!("missing host_base_path")));
-        };
-
         let mut valid_configs = vec![];
         for capability in &request.volume_capabilities {
             let (config, fs) = parse_volume_capability(capability)?;
@@ -127,44 +937,161 @@ impl Controller for ControllerService {
             }
         }
         let filesystem = filesystem.unwrap_or_default();
-
-        if request.name.contains("/..")
-            || request.name.contains("../")
-            || request.name == ".."
-            || request.name == "."
-            || request.name == "./"
+        if wipe_on_delete != WipeMode::None {
+            if delete_policy != DeletePolicy::Delete {
+                return Err(Status::invalid_argument(
+                    "wipe_on_delete requires delete_policy 'delete'",
+                ));
+            }
+            if filesystem == Filesystem::Bind {
+                return Err(Status::invalid_argument(
+                    "wipe_on_delete is not supported for bind volumes",
+                ));
+            }
+        }
+        if fsck != FsckPolicy::None && matches!(filesystem, Filesystem::Bind | Filesystem::Tmpfs) {
+            return Err(Status::invalid_argument(
+                "fsck is only supported for ext4 and xfs volumes",
+            ));
+        }
+        if (direct_io || logical_block_size.is_some())
+            && matches!(filesystem, Filesystem::Bind | Filesystem::Tmpfs)
         {
-            return Err(Status::invalid_argument(format!("invalid volume name")));
+            return Err(Status::invalid_argument(
+                "direct_io and logical_block_size are only supported for ext4 and xfs volumes",
+            ));
         }
-        let mut host_base_path = &*host_base_path;
-        while host_base_path.ends_with("/") {
-            host_base_path = &host_base_path[..host_base_path.len() - 1];
+        let io_limits = (io_limits != IoLimits::default()).then_some(io_limits);
+        if io_limits.is_some() && matches!(filesystem, Filesystem::Bind | Filesystem::Tmpfs) {
+            return Err(Status::invalid_argument(
+                "I/O limits are only supported for ext4 and xfs volumes",
+            ));
         }
-        while host_base_path.starts_with("/") {
-            host_base_path = &host_base_path[1..];
+        if allow_shrink && filesystem != Filesystem::Ext4 {
+            return Err(Status::invalid_argument(format!(
+                "allow_shrink is only supported for ext4 volumes, {filesystem:?} filesystems can't be shrunk"
+            )));
+        }
+        let size = match request.capacity_range {
+            None => 1073741824, // 1 GiB
+            Some(capacity) => capacity.required_bytes as u64,
+        };
+
+        let autogrow = match (autogrow_threshold, autogrow_max_size) {
+            (None, None) => None,
+            (Some(threshold), Some(max_size)) => {
+                if filesystem == Filesystem::Bind {
+                    return Err(Status::invalid_argument(
+                        "autogrow is not supported for bind volumes",
+                    ));
+                }
+                if pvc_name.is_none() || pvc_namespace.is_none() {
+                    return Err(Status::invalid_argument(
+                        "autogrow requires csi-provisioner's --extra-create-metadata",
+                    ));
+                }
+                if autogrow_increment == 0 || max_size < size {
+                    return Err(Status::invalid_argument(
+                        "autogrow_increment must be positive and autogrow_max_size at least the volume's size",
+                    ));
+                }
+                Some(Autogrow {
+                    threshold,
+                    increment: autogrow_increment,
+                    max_size,
+                })
+            }
+            _ => {
+                return Err(Status::invalid_argument(
+                    "autogrow requires both autogrow_threshold and autogrow_max_size",
+                ))
+            }
+        };
+
+        let pool = resolve_pool(pool.as_deref(), host_base_path.as_deref())?;
+        if let Some((name, pool)) = pool {
+            check_pool(name, pool, filesystem, size, &request.name).await?;
         }
-        let host_path = format!("{}/{}", host_base_path, request.name);
+        let host_base_path = host_base_path.or_else(|| Some(pool?.1.path.clone()));
+        let mut reservation_held = None;
+        let host_path = match &path_pattern {
+            Some(pattern) if filesystem != Filesystem::Tmpfs => {
+                let relative = render_path_pattern(
+                    pattern,
+                    &request.name,
+                    pvc_name.as_deref(),
+                    pvc_namespace.as_deref(),
+                    pv_name.as_deref(),
+                )?;
+                let host_path = volume_host_path(host_base_path.as_deref(), &relative, filesystem)?;
+                let reservation = store::PathReservation {
+                    host_path: host_path.clone(),
+                    base_path: host_base_path.clone().unwrap_or_default(),
+                    pool: pool.map(|(name, _)| name.clone()),
+                    volume: request.name.clone(),
+                };
+                reserve_host_path(&reservation, pool.map(|(name, _)| &**name)).await?;
+                reservation_held = Some(reservation);
+                host_path
+            }
+            _ => volume_host_path(host_base_path.as_deref(), &request.name, filesystem)?,
+        };
+        // a reservation the volume didn't end up with is given back
+        let release = |reservation: Option<store::PathReservation>| async move {
+            let Some(reservation) = reservation else {
+                return;
+            };
+            if let Err(e) = reservation.delete().await {
+                error!(
+                    "failed to release host path '{}': {e:#}",
+                    reservation.host_path
+                );
+            }
+        };
 
         let new_volume = store::Volume {
             name: request.name,
-            size: match request.capacity_range {
-                None => 1073741824, // 1 GiB
-                Some(capacity) => capacity.required_bytes as u64,
-            },
+            size,
             assigned_node_id: None,
             state: VolumeState::Open,
             published_readonly: false,
             published_config: None,
             host_path,
+            pool: pool.map(|(name, _)| name.clone()),
             filesystem,
             valid_configs,
             loop_device: None,
             mount_paths: vec![],
+            ephemeral: false,
+            pvc_name,
+            pvc_namespace,
+            pv_name,
+            delete_policy,
+            wipe_on_delete,
+            wipe_offset: 0,
+            fsck,
+            condition: None,
+            needs_fs_resize: false,
+            allow_shrink,
+            refused_shrink: None,
+            autogrow,
+            direct_io,
+            logical_block_size,
+            io_limits,
+            throttled_device: None,
+            uid,
+            gid,
+            mode,
+            adopted: false,
+        };
+        let creation = match new_volume.create().await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("failed to save volume for creation: {e:#}");
+                release(reservation_held).await;
+                return Err(Status::internal("internal failure"));
+            }
         };
-        let creation = new_volume.create().await.map_err(|e| {
-            error!("failed to save volume for creation: {e:#}");
-            Status::internal("internal failure")
-        })?;
         match creation {
             VolumeCreation::AlreadyExists => {
                 let Some(existing) = store::Volume::load(&new_volume.name).await.map_err(|e| {
@@ -180,13 +1107,16 @@ impl Controller for ControllerService {
                     && existing.assigned_node_id == new_volume.assigned_node_id
                     && existing.size == new_volume.size)
                 {
+                    if existing.host_path != new_volume.host_path {
+                        release(reservation_held).await;
+                    }
                     return Err(Status::already_exists("volume name already exists"));
                 }
                 return Ok(Response::new(CreateVolumeResponse {
                     volume: Some(Volume {
                         capacity_bytes: new_volume.size as i64,
+                        volume_context: volume_context(&new_volume),
                         volume_id: new_volume.name,
-                        volume_context: Default::default(),
                         content_source: None,
                         accessible_topology: vec![Topology {
                             segments: new_volume.assigned_node_id.map(|x| [("node".to_string(), x)].into_iter().collect()).unwrap_or_default(),
@@ -201,8 +1131,8 @@ impl Controller for ControllerService {
         Ok(Response::new(CreateVolumeResponse {
             volume: Some(Volume {
                 capacity_bytes: new_volume.size as i64,
+                volume_context: volume_context(&new_volume),
                 volume_id: new_volume.name,
-                volume_context: Default::default(),
                 content_source: None,
                 accessible_topology: vec![Topology {
                     segments: new_volume.assigned_node_id.map(|x| [("node".to_string(), x)].into_iter().collect()).unwrap_or_default(),
@@ -226,27 +1156,7 @@ impl Controller for ControllerService {
             Status::internal("internal failure")
         })?;
         if let Some(volume) = volume {
-            if !matches!(volume.state, VolumeState::Open) {
-                return Err(Status::failed_precondition(format!(
-                    "volume is in use, state = {:?}",
-                    volume.state
-                )));
-            }
-            let total_path = CONFIG.host_prefix.join(&volume.host_path);
-            if volume.filesystem == Filesystem::Bind {
-                tokio::fs::remove_dir_all(&total_path).await
-            } else {
-                tokio::fs::remove_file(&total_path).await
-            }
-            .map_err(|e| {
-                error!("failed to delete volume file: {e:#}");
-                Status::internal("internal failure")
-            })?;
-
-            volume.delete().await.map_err(|e| {
-                error!("failed to delete volume: {e:#}");
-                Status::internal("internal failure")
-            })?;
+            destroy_volume(volume).await?;
         }
         Ok(Response::new(DeleteVolumeResponse {}))
     }
@@ -267,9 +1177,32 @@ impl Controller for ControllerService {
             return Err(Status::invalid_argument("no capabilities specified"));
         }
 
+        let Some(capability) = &request.volume_capability else {
+            return Err(Status::invalid_argument("missing volume_capability"));
+        };
+        let (config, filesystem) = parse_volume_capability(capability)?;
+
         let mut volume = match store::Volume::load(&request.volume_id).await {
             Ok(Some(x)) => x,
-            Ok(None) => return Err(Status::not_found("volume_id not found")),
+            Ok(None) => match parse_static_volume(&request.volume_context, filesystem)? {
+                Some(static_volume) if static_volume.adopt => {
+                    adopt_volume(
+                        &request.volume_id,
+                        &request.node_id,
+                        &static_volume,
+                        config.clone(),
+                        VolumeState::Open,
+                    )
+                    .await?
+                }
+                // not managed by lvp, the node mounts it straight from the volume_context
+                Some(_) => {
+                    return Ok(Response::new(ControllerPublishVolumeResponse {
+                        publish_context: Default::default(),
+                    }))
+                }
+                None => return Err(Status::not_found("volume_id not found")),
+            },
             Err(e) => {
                 error!("failed to load volume for deletion: {e:#}");
                 return Err(Status::internal("internal failure"));
@@ -281,10 +1214,6 @@ impl Controller for ControllerService {
                 volume.assigned_node_id.as_ref().unwrap()
             )));
         }
-        let Some(capability) = &request.volume_capability else {
-            return Err(Status::invalid_argument("missing volume_capability"));
-        };
-        let (config, filesystem) = parse_volume_capability(capability)?;
 
         match volume.state {
             VolumeState::NodePublished => {
@@ -292,6 +1221,9 @@ impl Controller for ControllerService {
                     "volume currently mounted on node",
                 ));
             }
+            VolumeState::Wiping => {
+                return Err(Status::failed_precondition("volume is being deleted"));
+            }
             VolumeState::ControllerPublished => {
                 if !volume.valid_configs.contains(&config)
                     || filesystem
@@ -351,7 +1283,9 @@ impl Controller for ControllerService {
                     "volume currently mounted on node",
                 ))
             }
-            VolumeState::Open => return Ok(Response::new(ControllerUnpublishVolumeResponse {})),
+            VolumeState::Open | VolumeState::Wiping => {
+                return Ok(Response::new(ControllerUnpublishVolumeResponse {}))
+            }
             VolumeState::ControllerPublished => (),
         }
 
@@ -436,7 +1370,7 @@ impl Controller for ControllerService {
                 volume: Some(Volume {
                     capacity_bytes: volume.size as i64,
                     volume_id: volume.name.clone(),
-                    volume_context: Default::default(),
+                    volume_context: volume_context(&volume),
                     content_source: None,
                     accessible_topology: vec![Topology {
                         segments: volume.assigned_node_id.clone().map(|x| [("node".to_string(), x)].into_iter().collect()).unwrap_or_default(),
@@ -448,7 +1382,7 @@ impl Controller for ControllerService {
                     } else {
                         vec![]
                     },
-                    volume_condition: None,
+                    volume_condition: Some(volume_condition(volume.condition)),
                 }),
             });
             if request.max_entries > 0 && request.max_entries as usize <= entries.len() {
@@ -479,10 +1413,14 @@ impl Controller for ControllerService {
             }
         }
 
+        let mut pool = None::<String>;
         let mut host_base_path = None::<String>;
         for (name, value) in &request.parameters {
             match &**name {
+                "pool" => pool = Some(value.clone()),
                 "host_base_path" => host_base_path = Some(value.clone()),
+                // don't affect capacity
+                x if VOLUME_PARAMETERS.contains(&x) => (),
                 _ => {
                     return Err(Status::invalid_argument(format!(
                         "unknown parameter {name}"
@@ -490,7 +1428,8 @@ impl Controller for ControllerService {
                 }
             }
         }
-        let Some(host_base_path) = host_base_path else {
+        let Some((name, resolved)) = resolve_pool(pool.as_deref(), host_base_path.as_deref())?
+        else {
             return Ok(Response::new(GetCapacityResponse {
                 available_capacity: 0,
                 maximum_volume_size: None,
@@ -498,17 +1437,31 @@ impl Controller for ControllerService {
             }));
         };
 
-        let stats = crate::statfs::statfs(&Path::new(&host_base_path))
-            .await
-            .map_err(|e| {
-                error!("failed to get fs stats: {e:#}");
-                Status::internal("failed to get fs stats")
-            })?;
+        let mut available_capacity = match resolved.backend {
+            PoolBackend::Disk => {
+                let path = host_base_path.as_deref().unwrap_or(&resolved.path);
+                let stats = crate::statfs::statfs(&CONFIG.host_prefix.join(path.trim_matches('/')))
+                    .await
+                    .map_err(|e| {
+                        error!("failed to get fs stats: {e:#}");
+                        Status::internal("failed to get fs stats")
+                    })?;
+                stats.blocks_free_unprivileged * stats.block_size
+            }
+            PoolBackend::Memory => memory_available().await.map_err(|e| {
+                error!("failed to get memory stats: {e:#}");
+                Status::internal("failed to get memory stats")
+            })?,
+        };
+        if let Some(capacity) = resolved.capacity {
+            available_capacity =
+                available_capacity.min(capacity.saturating_sub(pool_usage(name, "").await?));
+        }
 
         Ok(Response::new(GetCapacityResponse {
-            available_capacity: (stats.blocks_free_unprivileged * stats.block_size) as i64,
-            maximum_volume_size: None,
-            minimum_volume_size: None,
+            available_capacity: available_capacity as i64,
+            maximum_volume_size: resolved.max_volume_size.map(|x| x as i64),
+            minimum_volume_size: resolved.min_volume_size.map(|x| x as i64),
         }))
     }
 
@@ -558,6 +1511,16 @@ impl Controller for ControllerService {
                         r#type: RpcType::PublishReadonly as i32,
                     })),
                 },
+                ControllerServiceCapability {
+                    r#type: Some(CapabilityType::Rpc(Rpc {
+                        r#type: RpcType::VolumeCondition as i32,
+                    })),
+                },
+                ControllerServiceCapability {
+                    r#type: Some(CapabilityType::Rpc(Rpc {
+                        r#type: RpcType::ExpandVolume as i32,
+                    })),
+                },
             ],
         }))
     }
@@ -603,9 +1566,89 @@ impl Controller for ControllerService {
         request: Request<ControllerExpandVolumeRequest>,
     ) -> Result<Response<ControllerExpandVolumeResponse>, Status> {
         let request = request.into_inner();
-        info!("controller_expand_volume = {request:#?}");
 
-        Err(Status::unimplemented("controller requires node expansion"))
+        if request.volume_id.is_empty() {
+            return Err(Status::invalid_argument("missing volume_id"));
+        }
+        let _shrink_hold = shrink::hold(&request.volume_id)
+            .ok_or_else(|| Status::unavailable("volume is being shrunk"))?;
+
+        let mut volume = match store::Volume::load(&request.volume_id).await {
+            Ok(Some(x)) => x,
+            Ok(None) => return Err(Status::not_found("volume_id not found")),
+            Err(e) => {
+                error!("failed to load volume for expansion: {e:#}");
+                return Err(Status::internal("internal failure"));
+            }
+        };
+
+        let target_capacity = match request.capacity_range {
+            None => 1073741824, // 1 GiB
+            Some(capacity) => capacity.required_bytes as u64,
+        };
+        let published = volume.state == VolumeState::NodePublished;
+        if target_capacity <= volume.size {
+            return Ok(Response::new(ControllerExpandVolumeResponse {
+                capacity_bytes: volume.size as i64,
+                node_expansion_required: published && volume.needs_fs_resize,
+            }));
+        }
+
+        if let Some(pool) = &volume.pool {
+            if let Some((name, pool)) = CONFIG.pools.get_key_value(pool) {
+                check_pool(name, pool, volume.filesystem, target_capacity, &volume.name).await?;
+            }
+        }
+
+        // mounted volumes are grown online by the node
+        if published && volume.filesystem != Filesystem::Bind {
+            return Ok(Response::new(ControllerExpandVolumeResponse {
+                capacity_bytes: target_capacity as i64,
+                node_expansion_required: true,
+            }));
+        }
+
+        if matches!(volume.filesystem, Filesystem::Ext4 | Filesystem::Xfs) {
+            match &volume.assigned_node_id {
+                Some(node) if node != &*NODE => {
+                    return Err(Status::failed_precondition(format!(
+                        "volume data lives on node {node}"
+                    )));
+                }
+                // images are only made on first publish, at whatever size is recorded by then
+                None => (),
+                Some(_) => {
+                    let volume_path = VolumePath::of(&volume).map_err(|e| {
+                        error!("invalid path for volume {}: {e}", volume.name);
+                        Status::internal("invalid volume path")
+                    })?;
+                    match volume_path.set_len(target_capacity).await {
+                        Ok(()) => volume.needs_fs_resize = true,
+                        Err(e) if e.kind() == ErrorKind::NotFound => (),
+                        Err(e) => {
+                            error!("failed to grow image of volume {}: {e}", volume.name);
+                            return Err(Status::internal("failed to grow volume image"));
+                        }
+                    }
+                }
+            }
+        }
+
+        // bind volumes aren't limited, and unmounted tmpfs volumes are sized on mount
+        volume.size = target_capacity;
+        volume.update().await.map_err(|e| {
+            error!("failed to save volume: {e:#}");
+            Status::internal("failed to save volume")
+        })?;
+        info!(
+            "expanded unpublished volume {} to {target_capacity} bytes",
+            volume.name
+        );
+
+        Ok(Response::new(ControllerExpandVolumeResponse {
+            capacity_bytes: target_capacity as i64,
+            node_expansion_required: false,
+        }))
     }
 
     async fn controller_get_volume(
@@ -629,7 +1672,7 @@ impl Controller for ControllerService {
         let raw_volume = Volume {
             capacity_bytes: volume.size as i64,
             volume_id: volume.name.clone(),
-            volume_context: Default::default(),
+            volume_context: volume_context(&volume),
             content_source: None,
             accessible_topology: vec![Topology {
                 segments: volume.assigned_node_id.clone().map(|x| [("node".to_string(), x)].into_iter().collect()).unwrap_or_default(),
@@ -641,7 +1684,7 @@ impl Controller for ControllerService {
             } else {
                 vec![]
             },
-            volume_condition: None,
+            volume_condition: Some(volume_condition(volume.condition)),
         };
 
         Ok(Response::new(ControllerGetVolumeResponse {
@@ -0,0 +1,311 @@
+use std::{
+    ffi::CString,
+    fmt,
+    fs::File,
+    os::{
+        fd::AsRawFd,
+        unix::{ffi::OsStrExt, fs::MetadataExt},
+    },
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use tokio::process::Command;
+use tonic::{Code, Status};
+
+use crate::{
+    config::{Executor, CONFIG},
+    mounts::{self, Propagation},
+};
+
+const HOST_MOUNT_NAMESPACE: &str = "/proc/1/ns/mnt";
+const HOST_ROOT: &str = "/proc/1/root";
+
+/// Makes `command` run in the host's mount namespace, or within the chroot
+fn prepare(command: &mut Command) -> Result<()> {
+    let root = CString::new("/")?;
+    match &CONFIG.executor {
+        Executor::HostNamespace => {
+            let namespace = File::open(HOST_MOUNT_NAMESPACE)
+                .with_context(|| format!("failed to open {HOST_MOUNT_NAMESPACE}"))?;
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNS) < 0
+                        || libc::chdir(root.as_ptr()) < 0
+                    {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        Executor::Chroot { path, .. } => {
+            let path = CString::new(path.as_os_str().as_bytes())?;
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::chroot(path.as_ptr()) < 0 || libc::chdir(root.as_ptr()) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Bytes of output kept for errors, from the end where tools print why they failed
+const OUTPUT_LIMIT: usize = 4096;
+
+/// A host tool that didn't run to completion
+#[derive(Debug)]
+pub enum CommandError {
+    /// Exited unsuccessfully, or was killed by a signal if `code` is `None`
+    Failed {
+        program: String,
+        code: Option<i32>,
+        /// stderr, or stdout for tools like fsck that report problems there
+        output: String,
+    },
+    /// Killed after running for longer than its timeout
+    TimedOut { program: String, timeout: Duration },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Failed {
+                program,
+                code: Some(code),
+                output,
+            } => write!(f, "{program} exited with code {code}: {output}"),
+            CommandError::Failed {
+                program, output, ..
+            } => write!(f, "{program} was killed: {output}"),
+            CommandError::TimedOut { program, timeout } => {
+                write!(f, "{program} timed out after {}s", timeout.as_secs())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl CommandError {
+    pub fn to_status(&self, context: &str) -> Status {
+        let code = match self {
+            CommandError::Failed { .. } => Code::Internal,
+            CommandError::TimedOut { .. } => Code::DeadlineExceeded,
+        };
+        Status::new(code, format!("{context}: {self}"))
+    }
+}
+
+/// Kills a command's whole process group unless it ran to completion, such as when the RPC running it is cancelled
+struct ProcessGroup(Option<u32>);
+
+impl ProcessGroup {
+    fn finished(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(id) = self.0 {
+            warn!("killing process group {id}");
+            unsafe { libc::kill(-(id as libc::pid_t), libc::SIGKILL) };
+        }
+    }
+}
+
+fn output_tail(stdout: &[u8], stderr: &[u8]) -> String {
+    let output = if stderr.iter().all(u8::is_ascii_whitespace) {
+        stdout
+    } else {
+        stderr
+    };
+    let output = String::from_utf8_lossy(&output[output.len().saturating_sub(OUTPUT_LIMIT)..]);
+    output.trim().to_string()
+}
+
+/// Runs a host tool through the configured executor, returning its stdout. Fails with a [`CommandError`]
+/// if it exits unsuccessfully or runs past its timeout from `commands` in the config.
+pub async fn run(command: &[&str]) -> Result<String> {
+    let timeout = CONFIG.commands.timeout_for(command[0].trim());
+    run_with_timeout(command, Some(timeout)).await
+}
+
+/// Like [`run`], for tools that can damage a filesystem if killed midway. They are only killed after a
+/// timeout set for them in `commands.timeouts`.
+pub async fn run_uninterrupted(command: &[&str]) -> Result<String> {
+    let timeout = CONFIG.commands.explicit_timeout(command[0].trim());
+    run_with_timeout(command, timeout).await
+}
+
+async fn run_with_timeout(command: &[&str], timeout: Option<Duration>) -> Result<String> {
+    let command = command.iter().map(|x| x.trim()).collect::<Vec<_>>();
+    let program = command[0];
+    info!("running {}", command.join(" "));
+    let mut process = Command::new(program);
+    process
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    unsafe {
+        // in a group of its own, so anything it forks is killed along with it
+        process.pre_exec(|| {
+            if libc::setpgid(0, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    prepare(&mut process)?;
+
+    let started = Instant::now();
+    let child = process
+        .spawn()
+        .with_context(|| format!("failed to spawn {program}"))?;
+    let group = ProcessGroup(child.id());
+    let waited = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, child.wait_with_output()).await,
+        None => Ok(child.wait_with_output().await),
+    };
+    let out = match waited {
+        Ok(x) => {
+            group.finished();
+            x?
+        }
+        Err(_) => {
+            drop(group);
+            let timeout = timeout.unwrap_or_default();
+            return Err(CommandError::TimedOut {
+                program: program.to_string(),
+                timeout,
+            }
+            .into());
+        }
+    };
+    info!(
+        "{program} exited with {} after {}ms",
+        out.status,
+        started.elapsed().as_millis()
+    );
+    if !out.status.success() {
+        return Err(CommandError::Failed {
+            program: program.to_string(),
+            code: out.status.code(),
+            output: output_tail(&out.stdout, &out.stderr),
+        }
+        .into());
+    }
+    Ok(String::from_utf8(out.stdout)?)
+}
+
+/// Path of a file in this container as seen by tools the executor runs
+pub fn tool_path(path: &Path) -> PathBuf {
+    match &CONFIG.executor {
+        // the host can only see into this container through our root
+        Executor::HostNamespace => Path::new("/proc")
+            .join(std::process::id().to_string())
+            .join("root")
+            .join(path.strip_prefix("/").unwrap_or(path)),
+        // binds keep the same layout
+        Executor::Chroot { .. } => path.to_path_buf(),
+    }
+}
+
+/// Directory device nodes are opened through, as this container's `/dev` may lack loop devices
+pub fn device_root() -> PathBuf {
+    match &CONFIG.executor {
+        Executor::HostNamespace => Path::new(HOST_ROOT).join("dev"),
+        Executor::Chroot { path, .. } => path.join("dev"),
+    }
+}
+
+/// Path of a device node, such as `/dev/loop0`, from within this container
+pub fn device_path(path: &Path) -> PathBuf {
+    device_root().join(path.strip_prefix("/dev").unwrap_or(path))
+}
+
+/// Top-level mounts beneath `path`, the ones that detach everything else with them
+async fn mounts_beneath(path: &Path) -> Result<Vec<PathBuf>> {
+    let beneath: Vec<PathBuf> = mounts::mounts()
+        .await?
+        .into_iter()
+        .map(|x| x.mount_point)
+        .filter(|x| x.starts_with(path) && x != path)
+        .collect();
+    let mut out: Vec<PathBuf> = beneath
+        .iter()
+        .filter(|x| !beneath.iter().any(|y| y != *x && x.starts_with(y)))
+        .cloned()
+        .collect();
+    out.dedup();
+    Ok(out)
+}
+
+async fn detach_chroot(path: &Path) -> Result<()> {
+    for mount in mounts_beneath(path).await? {
+        // binds of shared mounts would propagate the unmount to the host's own mounts
+        mounts::set_propagation(&mount, Propagation::Private, true).await?;
+        mounts::unmount(&mount, true).await?;
+    }
+    Ok(())
+}
+
+/// Prepares the executor: checks the host namespace is reachable, or assembles the chroot
+pub async fn init() -> Result<()> {
+    match &CONFIG.executor {
+        Executor::HostNamespace => {
+            let host = tokio::fs::metadata(HOST_MOUNT_NAMESPACE)
+                .await
+                .with_context(|| format!("failed to stat {HOST_MOUNT_NAMESPACE}"))?;
+            let own = tokio::fs::metadata("/proc/self/ns/mnt").await?;
+            if host.ino() == own.ino() {
+                bail!("PID 1 shares our mount namespace, the host_namespace executor requires hostPID");
+            }
+        }
+        Executor::Chroot { path, binds } => {
+            tokio::fs::create_dir_all(path).await?;
+            let leftover = mounts_beneath(path).await?;
+            if !leftover.is_empty() {
+                warn!(
+                    "found {} leftover mounts in {} from a previous run, detaching",
+                    leftover.len(),
+                    path.display()
+                );
+                detach_chroot(path).await?;
+            }
+
+            for bind in binds {
+                let from = Path::new("/").join(bind);
+                let out = path.join(bind);
+                tokio::fs::create_dir_all(&out).await?;
+                mounts::bind(&from, &out, false, true).await?;
+                // never propagate anything from within the chroot back to the host
+                mounts::set_propagation(&out, Propagation::Slave, true).await?;
+            }
+            let dev = path.join("dev");
+            tokio::fs::create_dir_all(&dev).await?;
+            mounts::mount(Path::new("none"), &dev, "devtmpfs", false, None).await?;
+            mounts::set_propagation(&dev, Propagation::Private, false).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Tears down the chroot on shutdown
+pub async fn cleanup() -> Result<()> {
+    if let Executor::Chroot { path, .. } = &CONFIG.executor {
+        info!("detaching chroot {}", path.display());
+        detach_chroot(path).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::{
+    executor::{run, run_uninterrupted, CommandError},
+    store::{Filesystem, FsckPolicy},
+};
+
+/// A filesystem with errors that weren't, or couldn't be, repaired automatically
+#[derive(Debug)]
+pub struct Corrupt {
+    pub device: PathBuf,
+    pub details: String,
+}
+
+impl fmt::Display for Corrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "filesystem on {} has errors that need a manual repair: {}",
+            self.device.display(),
+            self.details
+        )
+    }
+}
+
+impl std::error::Error for Corrupt {}
+
+/// Exit code of a command run through the executor, along with its output when it failed
+pub fn exit_code(result: Result<String>) -> Result<(i32, String)> {
+    match result {
+        Ok(_) => Ok((0, String::new())),
+        Err(e) => match e.downcast::<CommandError>()? {
+            CommandError::Failed {
+                code: Some(code),
+                output,
+                ..
+            } => Ok((code, output)),
+            e => Err(e.into()),
+        },
+    }
+}
+
+/// Checks, and with [`FsckPolicy::Repair`] repairs, the filesystem on an unmounted loop device.
+/// Fails with [`Corrupt`] if it shouldn't be mounted.
+pub async fn check(device: &Path, filesystem: Filesystem, policy: FsckPolicy) -> Result<()> {
+    if policy == FsckPolicy::None {
+        return Ok(());
+    }
+    let path = device.to_str().unwrap_or_default();
+    let corrupt = |details: String| Corrupt {
+        device: device.to_path_buf(),
+        details,
+    };
+    match filesystem {
+        Filesystem::Ext4 => {
+            let (code, output) = if policy == FsckPolicy::Repair {
+                exit_code(run_uninterrupted(&["e2fsck", "-p", path]).await)?
+            } else {
+                // `-n` can't replay the journal an unclean shutdown leaves behind, and would call it corrupt
+                let (code, output) = exit_code(
+                    run_uninterrupted(&["e2fsck", "-p", "-E", "journal_only", path]).await,
+                )?;
+                if code > 1 {
+                    anyhow::bail!("failed to replay the journal on {path}, e2fsck exited with code {code}: {output}");
+                }
+                exit_code(run(&["e2fsck", "-n", path]).await)?
+            };
+            match code {
+                0 => (),
+                // errors corrected, the reboot only matters for a root filesystem
+                1 | 2 => warn!("e2fsck repaired errors on {path}: {output}"),
+                4 => return Err(corrupt(output).into()),
+                _ => anyhow::bail!("e2fsck failed on {path} with code {code}: {output}"),
+            }
+        }
+        Filesystem::Xfs => {
+            let result = if policy == FsckPolicy::Repair {
+                run_uninterrupted(&["xfs_repair", path]).await
+            } else {
+                run(&["xfs_repair", "-n", path]).await
+            };
+            let (code, output) = exit_code(result)?;
+            match code {
+                0 => (),
+                1 => return Err(corrupt(output).into()),
+                // the log is replayed by mounting, as after any unclean shutdown
+                2 => info!("xfs log on {path} is dirty, leaving it to be replayed on mount"),
+                _ => anyhow::bail!("xfs_repair failed on {path} with code {code}: {output}"),
+            }
+        }
+        Filesystem::Bind | Filesystem::Tmpfs => (),
+    }
+    Ok(())
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fmt,
+    io::ErrorKind,
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::{core::v1::PersistentVolume, storage::v1::CSINode};
+use kube::Api;
+use log::{error, info, warn};
+
+use crate::{
+    config::{PoolBackend, CONFIG, NODE},
+    controller::{destroy_volume, release_host_path},
+    identity::DRIVER_NAME,
+    paths::{self, VolumePath, TRASH_DIR},
+    store::{self, DeletePolicy, Filesystem, VolumeState, CLIENT},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Orphan {
+    /// File or directory in a pool that no volume refers to
+    Data { root: PathBuf, relative: PathBuf },
+    /// Volume on this node whose backing file is gone
+    MissingData(String),
+    /// Volume on this node whose PV no longer exists in the cluster, dropped while keeping its data
+    MissingPv(String),
+    /// Reservation of a `path_pattern` host path whose volume and data are both gone
+    Reservation(String),
+}
+
+impl fmt::Display for Orphan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Orphan::Data { root, relative } => write!(
+                f,
+                "orphaned data '{}' without a volume",
+                root.join(relative).display()
+            ),
+            Orphan::MissingData(name) => write!(f, "volume {name} with missing data"),
+            Orphan::MissingPv(name) => write!(f, "volume {name} without a PV"),
+            Orphan::Reservation(host_path) => {
+                write!(f, "reservation of host path '{host_path}' without a volume")
+            }
+        }
+    }
+}
+
+/// Whether a pool entry is named like the volumes lvp provisions, `pvc-*` or inline `csi-*` ones
+fn is_volume_name(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.starts_with("pvc-") || name.starts_with("csi-")
+}
+
+/// Host paths of static PVs, which lvp mounts but doesn't own
+fn static_paths(pvs: &[PersistentVolume]) -> Vec<String> {
+    pvs.iter()
+        .filter_map(|pv| {
+            let csi = pv.spec.as_ref()?.csi.as_ref()?;
+            if csi.driver != DRIVER_NAME {
+                return None;
+            }
+            csi.volume_attributes.as_ref()?.get("host_path").cloned()
+        })
+        .collect()
+}
+
+/// Reservations whose volume no longer exists
+fn unused_reservations<'a>(
+    volumes: &[store::Volume],
+    reservations: &'a [store::PathReservation],
+) -> impl Iterator<Item = &'a store::PathReservation> {
+    let names: HashSet<String> = volumes.iter().map(|x| x.name.clone()).collect();
+    reservations
+        .iter()
+        .filter(move |x| !names.contains(&x.volume))
+}
+
+/// Walks every disk pool for entries lvp made that aren't, and don't lead to, a volume. Retained data
+/// and static PVs are left alone.
+async fn orphaned_data(
+    volumes: &[store::Volume],
+    pvs: &[PersistentVolume],
+    reservations: &[store::PathReservation],
+) -> Result<Vec<Orphan>> {
+    let pool_paths: Vec<PathBuf> = CONFIG
+        .pools
+        .values()
+        .filter(|x| x.backend == PoolBackend::Disk)
+        .filter_map(|x| paths::normalize(&x.path).ok())
+        .collect();
+
+    // everything relative to host_prefix
+    let mut known = HashSet::new();
+    let mut ancestors = HashSet::new();
+    let retained = store::Retained::list().await?;
+    let volume_paths = volumes
+        .iter()
+        .filter(|x| x.filesystem != Filesystem::Tmpfs)
+        .map(|x| x.host_path.clone())
+        .chain(retained.into_iter().map(|x| x.host_path))
+        .chain(static_paths(pvs))
+        .filter_map(|x| paths::normalize(&x).ok());
+    // nested pools are walked on their own
+    for path in volume_paths.chain(pool_paths.iter().cloned()) {
+        ancestors.extend(path.ancestors().skip(1).map(|x| x.to_path_buf()));
+        known.insert(path);
+    }
+    // paths rendered from a `path_pattern` aren't named like volumes, only their reservation gives them away
+    let reserved: HashSet<PathBuf> = unused_reservations(volumes, reservations)
+        .filter_map(|x| paths::normalize(&x.host_path).ok())
+        .collect();
+    for path in &reserved {
+        ancestors.extend(path.ancestors().skip(1).map(|x| x.to_path_buf()));
+    }
+
+    let mut out = vec![];
+    for pool_path in &pool_paths {
+        let root = CONFIG.host_prefix.join(pool_path);
+        let root_dev = match tokio::fs::metadata(&root).await {
+            Ok(x) => x.dev(),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let mut pending = vec![pool_path.clone()];
+        while let Some(dir) = pending.pop() {
+            let mut entries = tokio::fs::read_dir(CONFIG.host_prefix.join(&dir)).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = dir.join(entry.file_name());
+                if &dir == pool_path && entry.file_name() == TRASH_DIR {
+                    continue;
+                }
+                let metadata = entry.metadata().await?;
+                // never touch anything reached through a symlink or another mount
+                if metadata.is_symlink() || metadata.dev() != root_dev || known.contains(&path) {
+                    continue;
+                }
+                if metadata.is_dir() && ancestors.contains(&path) {
+                    pending.push(path);
+                    continue;
+                }
+                if !is_volume_name(&entry.file_name()) && !reserved.contains(&path) {
+                    continue;
+                }
+                out.push(Orphan::Data {
+                    root: root.clone(),
+                    relative: path.strip_prefix(pool_path)?.to_path_buf(),
+                });
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Volumes made on this node whose image or directory no longer exists
+async fn missing_data(volumes: &[store::Volume]) -> Vec<Orphan> {
+    let mut out = vec![];
+    for volume in volumes {
+        // volumes are only made on first publish
+        if volume.assigned_node_id.as_deref() != Some(&**NODE)
+            || volume.filesystem == Filesystem::Tmpfs
+            || volume.state == VolumeState::Wiping
+        {
+            continue;
+        }
+        let exists = match VolumePath::of(volume) {
+            Ok(x) => x.exists().await,
+            Err(e) => Err(e),
+        };
+        match exists {
+            Ok(true) => (),
+            Ok(false) => out.push(Orphan::MissingData(volume.name.clone())),
+            Err(e) => error!("failed to check data of volume {}: {e}", volume.name),
+        }
+    }
+    out
+}
+
+/// Reservations left behind by volumes that are gone along with their data, such as after a failed creation
+async fn stale_reservations(
+    volumes: &[store::Volume],
+    reservations: &[store::PathReservation],
+) -> Vec<Orphan> {
+    let mut out = vec![];
+    for reservation in unused_reservations(volumes, reservations) {
+        let exists = match VolumePath::new(&reservation.host_path, reservation.pool.as_deref()) {
+            Ok(x) => x.exists().await,
+            Err(e) => Err(e),
+        };
+        match exists {
+            Ok(false) => out.push(Orphan::Reservation(reservation.host_path.clone())),
+            // left for its data to be collected first
+            Ok(true) => (),
+            Err(e) => error!(
+                "failed to check data of reservation '{}': {e}",
+                reservation.host_path
+            ),
+        }
+    }
+    out
+}
+
+/// Releases the reservation of a host path, unless a volume has it again
+async fn release_unused(host_path: &str) -> Result<()> {
+    let Some(reservation) = store::PathReservation::load(host_path).await? else {
+        return Ok(());
+    };
+    if store::Volume::load(&reservation.volume).await?.is_some() {
+        return Ok(());
+    }
+    release_host_path(&reservation, true).await
+}
+
+/// Whether this node collects volumes that were never assigned to a node, the registered node with the
+/// lowest id doing it for all of them
+async fn owns_unassigned() -> Result<bool> {
+    let csi_nodes: Api<CSINode> = Api::all(CLIENT.clone());
+    let first = csi_nodes
+        .list(&Default::default())
+        .await?
+        .into_iter()
+        .flat_map(|x| x.spec.drivers)
+        .filter(|x| x.name == DRIVER_NAME)
+        .map(|x| x.node_id)
+        .min();
+    Ok(first.as_deref() == Some(&**NODE))
+}
+
+/// Volumes of this node whose PV was deleted without lvp being asked to delete them
+async fn missing_pvs(volumes: &[store::Volume], pvs: &[PersistentVolume]) -> Result<Vec<Orphan>> {
+    let handles: HashSet<&str> = pvs
+        .iter()
+        .filter_map(|pv| {
+            let csi = pv.spec.as_ref()?.csi.as_ref()?;
+            (csi.driver == DRIVER_NAME).then_some(&*csi.volume_handle)
+        })
+        .collect();
+    let owns_unassigned = owns_unassigned().await?;
+    Ok(volumes
+        .iter()
+        // inline volumes never have a PV
+        .filter(|x| !x.ephemeral && !handles.contains(&*x.name))
+        .filter(|x| match &x.assigned_node_id {
+            Some(node) => node == &*NODE,
+            None => owns_unassigned,
+        })
+        .map(|x| Orphan::MissingPv(x.name.clone()))
+        .collect())
+}
+
+async fn clean(orphan: &Orphan) -> Result<()> {
+    match orphan {
+        Orphan::Data { root, relative } => {
+            VolumePath::beneath(root, relative.clone()).remove().await?;
+            let host_path = root.strip_prefix(&CONFIG.host_prefix)?.join(relative);
+            release_unused(&host_path.display().to_string()).await?;
+        }
+        Orphan::MissingData(name) => {
+            let Some(volume) = store::Volume::load(name).await? else {
+                return Ok(());
+            };
+            if volume.state != VolumeState::Open {
+                return Err(anyhow!("volume is in use, state = {:?}", volume.state));
+            }
+            volume.delete().await?;
+        }
+        Orphan::MissingPv(name) => {
+            let Some(mut volume) = store::Volume::load(name).await? else {
+                return Ok(());
+            };
+            // a PV deleted by hand was likely one with the Retain reclaim policy, so keep its data
+            volume.delete_policy = DeletePolicy::Retain;
+            destroy_volume(volume)
+                .await
+                .map_err(|e| anyhow!("{}", e.message()))?;
+        }
+        Orphan::Reservation(host_path) => release_unused(host_path).await?,
+    }
+    Ok(())
+}
+
+async fn collect(first_seen: &mut HashMap<Orphan, Instant>) -> Result<()> {
+    let volumes = store::Volume::list().await?;
+    let pvs: Api<PersistentVolume> = Api::all(CLIENT.clone());
+    let pvs = pvs.list(&Default::default()).await?.items;
+    let reservations = store::PathReservation::list().await?;
+    let mut orphans = orphaned_data(&volumes, &pvs, &reservations).await?;
+    orphans.extend(missing_data(&volumes).await);
+    orphans.extend(stale_reservations(&volumes, &reservations).await);
+    orphans.extend(missing_pvs(&volumes, &pvs).await?);
+
+    let now = Instant::now();
+    let grace_period = Duration::from_secs(CONFIG.gc.grace_period);
+    // an orphan has to be seen on consecutive passes to be cleaned
+    first_seen.retain(|x, _| orphans.contains(x));
+    for orphan in orphans {
+        let seen_for = now - *first_seen.entry(orphan.clone()).or_insert(now);
+        if !CONFIG.gc.clean || seen_for < grace_period {
+            warn!("found {orphan}, seen for {}s", seen_for.as_secs());
+            continue;
+        }
+        info!("cleaning {orphan}");
+        if let Err(e) = clean(&orphan).await {
+            error!("failed to clean {orphan}: {e:#}");
+        }
+    }
+    Ok(())
+}
+
+/// Periodically reconciles the store against pool contents and PVs, reporting or cleaning orphans
+pub async fn run() {
+    if CONFIG.gc.interval == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(CONFIG.gc.interval);
+    let mut first_seen = HashMap::new();
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = collect(&mut first_seen).await {
+            error!("garbage collection failed: {e:#}");
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::{error, info, warn};
+
+use crate::{
+    config::{CONFIG, NODE},
+    loopdev::{self, AttachedLoop},
+    mounts::{self, MountInfo},
+    paths::VolumePath,
+    store::{self, Condition, Filesystem, VolumeState},
+};
+
+const KMSG: &str = "/dev/kmsg";
+
+/// Latest error the kernel logged for each attached loop device, by name such as `loop3`
+static KERNEL_ERRORS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// The loop device a kernel log message reports an error for, such as from
+/// `I/O error, dev loop3, sector 0`, `EXT4-fs error (device loop3)` or `XFS (loop3): Corruption detected`
+fn error_device(message: &str) -> Option<String> {
+    let lower = message.to_ascii_lowercase();
+    if !["error", "corruption", "shut down", "read-only"]
+        .iter()
+        .any(|x| lower.contains(x))
+    {
+        return None;
+    }
+    message.match_indices("loop").find_map(|(index, _)| {
+        let before = &message[..index];
+        let digits: String = message[index + 4..]
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect();
+        let is_device =
+            before.ends_with("dev ") || before.ends_with("device ") || before.ends_with('(');
+        (is_device && !digits.is_empty()).then(|| format!("loop{digits}"))
+    })
+}
+
+/// Reads kernel log records written since the last call
+fn read_kernel_log(kmsg: &mut File) -> io::Result<()> {
+    // every read returns a single record
+    let mut buffer = vec![0u8; 8192];
+    loop {
+        let len = match kmsg.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            // records were overwritten before we got to them
+            Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+            Err(e) => return Err(e),
+        };
+        let record = String::from_utf8_lossy(&buffer[..len]);
+        let Some((_, message)) = record.split_once(';') else {
+            continue;
+        };
+        let message = message.lines().next().unwrap_or_default();
+        if let Some(device) = error_device(message) {
+            warn!("kernel reported an error for {device}: {message}");
+            KERNEL_ERRORS
+                .lock()
+                .unwrap()
+                .insert(device, message.to_string());
+        }
+    }
+}
+
+/// Drops errors recorded for a loop device, once it is attached or detached and they no longer
+/// describe whatever it backs
+pub fn forget_device(path: &Path) {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    KERNEL_ERRORS.lock().unwrap().remove(&*name);
+}
+
+fn open_kernel_log() -> io::Result<File> {
+    let mut kmsg = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(KMSG)?;
+    // loop devices get reused, so errors from before we started can't be attributed
+    kmsg.seek(SeekFrom::End(0))?;
+    Ok(kmsg)
+}
+
+/// Problems with a volume's backing data, which exists from its first publish on this node on
+async fn data_problem(volume: &store::Volume) -> Option<String> {
+    if volume.assigned_node_id.as_deref() != Some(&**NODE)
+        || volume.filesystem == Filesystem::Tmpfs
+        || volume.state == VolumeState::Wiping
+    {
+        return None;
+    }
+    let exists = match VolumePath::of(volume) {
+        Ok(x) => x.exists().await,
+        Err(e) => Err(e),
+    };
+    match exists {
+        Ok(true) => None,
+        Ok(false) => Some("backing data is missing".to_string()),
+        Err(e) => Some(format!("backing data is inaccessible: {e}")),
+    }
+}
+
+/// Problems with a published volume's loop device and mounts
+fn publish_problems(
+    volume: &store::Volume,
+    mounts: &[MountInfo],
+    loops: &[AttachedLoop],
+) -> Vec<String> {
+    let mut out = vec![];
+    if let Some(loop_device) = &volume.loop_device {
+        if !loops.iter().any(|x| &x.path == loop_device) {
+            out.push(format!("loop device {} is detached", loop_device.display()));
+        }
+        let name = loop_device
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        if let Some(message) = KERNEL_ERRORS.lock().unwrap().get(&*name) {
+            out.push(format!(
+                "kernel reported errors for {}: {message}",
+                loop_device.display()
+            ));
+        }
+    }
+    for path in &volume.mount_paths {
+        match mounts.iter().rev().find(|x| &x.mount_point == path) {
+            None => out.push(format!("not mounted at '{}'", path.display())),
+            // `errors=remount-ro` flips the filesystem, not the mounts of it
+            Some(mount)
+                if mount.super_options.iter().any(|x| x == "ro")
+                    && !mount.options.iter().any(|x| x == "ro") =>
+            {
+                out.push("filesystem was remounted read-only, likely after errors".to_string());
+                break;
+            }
+            Some(_) => (),
+        }
+    }
+    out
+}
+
+async fn check(
+    volume: &store::Volume,
+    mounts: &[MountInfo],
+    loops: &[AttachedLoop],
+) -> Option<Condition> {
+    let mut problems: Vec<String> = data_problem(volume).await.into_iter().collect();
+    if volume.state == VolumeState::NodePublished {
+        problems.extend(publish_problems(volume, mounts, loops));
+    }
+    (!problems.is_empty()).then(|| Condition {
+        abnormal: true,
+        message: problems.join("; "),
+    })
+}
+
+/// Current condition of a volume published on this node, `None` if healthy
+pub async fn volume_condition(volume: &store::Volume) -> Result<Option<Condition>> {
+    let mounts = mounts::mounts().await?;
+    let loops = loopdev::attached().await?;
+    Ok(check(volume, &mounts, &loops).await)
+}
+
+async fn monitor_pass() -> Result<()> {
+    let mounts = mounts::mounts().await?;
+    let loops = loopdev::attached().await?;
+    // errors of detached devices don't carry over to whatever they're reused for
+    KERNEL_ERRORS.lock().unwrap().retain(|name, _| {
+        loops
+            .iter()
+            .any(|x| x.path.file_name().map(|x| x == &**name).unwrap_or_default())
+    });
+
+    for volume in store::Volume::list().await? {
+        if volume.assigned_node_id.as_deref() != Some(&**NODE) {
+            continue;
+        }
+        let condition = check(&volume, &mounts, &loops).await;
+        // only a publish clears conditions it found, like a failed fsck, on unpublished volumes
+        if condition == volume.condition
+            || (condition.is_none() && volume.state != VolumeState::NodePublished)
+        {
+            continue;
+        }
+        match &condition {
+            Some(x) => warn!("volume {} is abnormal: {}", volume.name, x.message),
+            None => info!("volume {} recovered", volume.name),
+        }
+        // publishes may save the volume at any time, only the condition is ours to change
+        let saved = store::Volume::modify(&volume.name, |x| x.condition = condition.clone()).await;
+        if let Err(e) = saved {
+            error!("failed to save condition of volume {}: {e:#}", volume.name);
+        }
+    }
+    Ok(())
+}
+
+/// Periodically checks the health of volumes on this node, recording their condition in the store
+pub async fn run() {
+    if CONFIG.health.interval == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(CONFIG.health.interval);
+    let mut kmsg = match open_kernel_log() {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warn!("failed to open {KMSG}, kernel errors won't be reported: {e}");
+            None
+        }
+    };
+    loop {
+        if let Some(file) = kmsg.take() {
+            let (file, result) = tokio::task::spawn_blocking(move || {
+                let mut file = file;
+                let result = read_kernel_log(&mut file);
+                (file, result)
+            })
+            .await
+            .expect("kernel log reader panicked");
+            match result {
+                Ok(()) => kmsg = Some(file),
+                Err(e) => error!("failed to read {KMSG}, kernel errors won't be reported: {e}"),
+            }
+        }
+        if let Err(e) = monitor_pass().await {
+            error!("health check failed: {e:#}");
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Whether `path` is the mount of an abnormal filesystem, for stats of volumes lvp doesn't track
+pub async fn remounted_read_only(path: &Path) -> Result<bool> {
+    let Some(mount) = mounts::find_mount(path).await? else {
+        return Ok(false);
+    };
+    Ok(mount.super_options.iter().any(|x| x == "ro") && !mount.options.iter().any(|x| x == "ro"))
+}
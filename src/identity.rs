@@ -8,6 +8,8 @@ use crate::proto::{
     ProbeRequest, ProbeResponse,
 };
 
+pub const DRIVER_NAME: &str = "lvp";
+
 #[derive(Debug)]
 pub struct IdentityService {}
 
@@ -18,7 +20,7 @@ impl Identity for IdentityService {
         _request: Request<GetPluginInfoRequest>,
     ) -> Result<Response<GetPluginInfoResponse>, Status> {
         Ok(Response::new(GetPluginInfoResponse {
-            name: "lvp".to_string(),
+            name: DRIVER_NAME.to_string(),
             vendor_version: env!("CARGO_PKG_VERSION").to_string(),
             manifest: Default::default(),
         }))
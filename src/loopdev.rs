@@ -0,0 +1,336 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+use crate::{config::Executor, executor, health};
+
+// linux/loop.h
+const LOOP_SET_FD: libc::c_ulong = 0x4C00;
+const LOOP_CLR_FD: libc::c_ulong = 0x4C01;
+const LOOP_SET_STATUS64: libc::c_ulong = 0x4C04;
+const LOOP_GET_STATUS64: libc::c_ulong = 0x4C05;
+const LOOP_SET_CAPACITY: libc::c_ulong = 0x4C07;
+const LOOP_SET_DIRECT_IO: libc::c_ulong = 0x4C08;
+const LOOP_SET_BLOCK_SIZE: libc::c_ulong = 0x4C09;
+const LOOP_CONFIGURE: libc::c_ulong = 0x4C0A;
+const LOOP_CTL_GET_FREE: libc::c_ulong = 0x4C82;
+
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+const LO_FLAGS_DIRECT_IO: u32 = 16;
+
+const LOOP_CONTROL: &str = "/dev/loop-control";
+/// Attempts at grabbing a free device before giving up, as concurrent attaches can race us for it
+const ATTACH_ATTEMPTS: usize = 8;
+
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; 64],
+    lo_crypt_name: [u8; 64],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+#[repr(C)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8],
+}
+
+#[derive(Debug)]
+pub enum LoopError {
+    /// `/dev/loop-control` is missing or unusable
+    Control(io::Error),
+    /// Every free device was taken by someone else before we could configure it
+    NoFreeDevice,
+    /// An ioctl on a loop device failed
+    Ioctl {
+        operation: &'static str,
+        device: PathBuf,
+        error: io::Error,
+    },
+}
+
+impl fmt::Display for LoopError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoopError::Control(e) => write!(f, "failed to use {LOOP_CONTROL}: {e}"),
+            LoopError::NoFreeDevice => write!(f, "no free loop device"),
+            LoopError::Ioctl {
+                operation,
+                device,
+                error,
+            } => write!(f, "{operation} failed on {}: {error}", device.display()),
+        }
+    }
+}
+
+impl std::error::Error for LoopError {}
+
+/// How a backing file is attached
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoopOptions {
+    /// Bypass the page cache of the backing file
+    pub direct_io: bool,
+    /// Logical sector size of the device, 512 bytes if unset
+    pub block_size: Option<u32>,
+}
+
+/// A freshly attached loop device. Devices are attached with autoclear, so this handle has to be held
+/// until the device is mounted, or it detaches again when dropped.
+pub struct LoopDevice {
+    /// Path of the device on the host, see [`executor::device_path`]
+    pub path: PathBuf,
+    _file: File,
+}
+
+/// A loop device with a backing file attached
+#[derive(Clone, Debug)]
+pub struct AttachedLoop {
+    /// Path of the device on the host, as recorded in `store::Volume::loop_device`
+    pub path: PathBuf,
+    /// `st_dev` and `st_ino` of the backing file
+    pub backing_device: u64,
+    pub backing_inode: u64,
+    /// Backing file path as reported by the kernel, for display only
+    pub backing_file: String,
+}
+
+/// The loop device a mount's source refers to, if any
+pub fn from_mount_source(source: &str) -> Option<PathBuf> {
+    let source = Path::new(source);
+    // mounts made by earlier versions went through the default chroot
+    let legacy_root = Executor::default_chroot_path().join("dev");
+    let path = [executor::device_root(), legacy_root]
+        .iter()
+        .find_map(|x| source.strip_prefix(x).ok())
+        .map(|x| Path::new("/dev").join(x))
+        .unwrap_or_else(|| source.to_path_buf());
+    let is_loop = path
+        .strip_prefix("/dev")
+        .ok()
+        .and_then(|x| x.to_str())
+        .map(|x| x.starts_with("loop") && x != "loop-control")
+        .unwrap_or_default();
+    is_loop.then_some(path)
+}
+
+fn ioctl<T>(file: &File, request: libc::c_ulong, arg: T) -> io::Result<libc::c_int> {
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), request, arg) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn open_device(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(executor::device_path(path))
+}
+
+fn status(path: &Path) -> io::Result<LoopInfo64> {
+    let file = File::open(executor::device_path(path))?;
+    let mut info: LoopInfo64 = unsafe { std::mem::zeroed() };
+    ioctl(&file, LOOP_GET_STATUS64, &mut info)?;
+    Ok(info)
+}
+
+/// Attaches with `LOOP_SET_FD` and `LOOP_SET_STATUS64` for kernels before 5.8, which lack `LOOP_CONFIGURE`
+fn attach_legacy(
+    device: &File,
+    path: &Path,
+    backing: &File,
+    flags: u32,
+    block_size: Option<u32>,
+) -> Result<(), LoopError> {
+    let ioctl_error = |operation, error| LoopError::Ioctl {
+        operation,
+        device: path.to_path_buf(),
+        error,
+    };
+    ioctl(device, LOOP_SET_FD, backing.as_raw_fd()).map_err(|e| ioctl_error("LOOP_SET_FD", e))?;
+    let configured = (|| {
+        let mut info: LoopInfo64 = unsafe { std::mem::zeroed() };
+        info.lo_flags = flags & LO_FLAGS_AUTOCLEAR;
+        ioctl(device, LOOP_SET_STATUS64, &info).map_err(|e| ioctl_error("LOOP_SET_STATUS64", e))?;
+        if let Some(block_size) = block_size {
+            ioctl(device, LOOP_SET_BLOCK_SIZE, block_size as libc::c_ulong)
+                .map_err(|e| ioctl_error("LOOP_SET_BLOCK_SIZE", e))?;
+        }
+        if flags & LO_FLAGS_DIRECT_IO != 0 {
+            ioctl(device, LOOP_SET_DIRECT_IO, 1 as libc::c_ulong)
+                .map_err(|e| ioctl_error("LOOP_SET_DIRECT_IO", e))?;
+        }
+        Ok(())
+    })();
+    if configured.is_err() {
+        ioctl(device, LOOP_CLR_FD, 0 as libc::c_ulong).ok();
+    }
+    configured
+}
+
+fn attach_blocking(backing: &File, options: LoopOptions) -> Result<LoopDevice, LoopError> {
+    let control =
+        File::open(executor::device_path(Path::new(LOOP_CONTROL))).map_err(LoopError::Control)?;
+    let mut flags = LO_FLAGS_AUTOCLEAR;
+    if options.direct_io {
+        flags |= LO_FLAGS_DIRECT_IO;
+    }
+    for _ in 0..ATTACH_ATTEMPTS {
+        let number =
+            ioctl(&control, LOOP_CTL_GET_FREE, 0 as libc::c_ulong).map_err(LoopError::Control)?;
+        let path = PathBuf::from(format!("/dev/loop{number}"));
+        let device = open_device(&path).map_err(|error| LoopError::Ioctl {
+            operation: "open",
+            device: path.clone(),
+            error,
+        })?;
+
+        let mut config: LoopConfig = unsafe { std::mem::zeroed() };
+        config.fd = backing.as_raw_fd() as u32;
+        config.info.lo_flags = flags;
+        config.block_size = options.block_size.unwrap_or_default();
+        let result = match ioctl(&device, LOOP_CONFIGURE, &config) {
+            Ok(_) => Ok(()),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOTTY)) => {
+                attach_legacy(&device, &path, backing, flags, options.block_size)
+            }
+            Err(error) => Err(LoopError::Ioctl {
+                operation: "LOOP_CONFIGURE",
+                device: path.clone(),
+                error,
+            }),
+        };
+        match result {
+            Ok(()) => {
+                return Ok(LoopDevice {
+                    path,
+                    _file: device,
+                })
+            }
+            // someone else grabbed the device in between
+            Err(LoopError::Ioctl { error, .. }) if error.raw_os_error() == Some(libc::EBUSY) => {
+                continue
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(LoopError::NoFreeDevice)
+}
+
+/// Attaches `backing` to a free loop device
+pub async fn attach(backing: File, options: LoopOptions) -> Result<LoopDevice, LoopError> {
+    let device = tokio::task::spawn_blocking(move || attach_blocking(&backing, options))
+        .await
+        .map_err(|e| LoopError::Control(io::Error::other(e)))??;
+    info!("attached loop device '{}'", device.path.display());
+    health::forget_device(&device.path);
+    if options.direct_io && !direct_io_active(&device.path).await {
+        // the kernel quietly falls back when the backing filesystem can't do it at this block size
+        warn!(
+            "loop device '{}' fell back to buffered I/O, direct I/O needs a block size matching the backing filesystem's",
+            device.path.display()
+        );
+    }
+    Ok(device)
+}
+
+async fn direct_io_active(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default();
+    let dio = Path::new("/sys/block").join(name).join("loop/dio");
+    matches!(tokio::fs::read_to_string(dio).await, Ok(x) if x.trim() == "1")
+}
+
+async fn device_ioctl(
+    path: &Path,
+    operation: &'static str,
+    request: libc::c_ulong,
+) -> Result<(), LoopError> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let ioctl_error = |error| LoopError::Ioctl {
+            operation,
+            device: path.clone(),
+            error,
+        };
+        let device = open_device(&path).map_err(ioctl_error)?;
+        ioctl(&device, request, 0 as libc::c_ulong).map_err(ioctl_error)?;
+        Ok(())
+    })
+    .await
+    .map_err(|e| LoopError::Control(io::Error::other(e)))?
+}
+
+/// Detaches a loop device, succeeding if it is already detached
+pub async fn detach(path: &Path) -> Result<(), LoopError> {
+    match device_ioctl(path, "LOOP_CLR_FD", LOOP_CLR_FD).await {
+        // autoclear already detached it once its last mount went away
+        Err(LoopError::Ioctl { error, .. }) if error.raw_os_error() == Some(libc::ENXIO) => {}
+        Err(LoopError::Ioctl { error, .. }) if error.kind() == io::ErrorKind::NotFound => {}
+        x => x?,
+    }
+    health::forget_device(path);
+    Ok(())
+}
+
+/// Picks up a resized backing file
+pub async fn set_capacity(path: &Path) -> Result<(), LoopError> {
+    device_ioctl(path, "LOOP_SET_CAPACITY", LOOP_SET_CAPACITY).await
+}
+
+/// Lists loop devices that currently have a backing file
+pub async fn attached() -> Result<Vec<AttachedLoop>> {
+    let mut out = vec![];
+    let mut entries = tokio::fs::read_dir("/sys/block").await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("loop") {
+            continue;
+        }
+        // only present while a file is attached
+        let backing_file =
+            match tokio::fs::read_to_string(entry.path().join("loop/backing_file")).await {
+                Ok(x) => x.trim_end().to_string(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+        let path = Path::new("/dev").join(&name);
+        let status_path = path.clone();
+        let info = match tokio::task::spawn_blocking(move || status(&status_path)).await? {
+            Ok(x) => x,
+            // detached since listing
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => continue,
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to get status of {}", path.display()))
+            }
+        };
+        out.push(AttachedLoop {
+            path,
+            backing_device: info.lo_device,
+            backing_inode: info.lo_inode,
+            backing_file,
+        });
+    }
+    Ok(out)
+}
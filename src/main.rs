@@ -19,18 +19,34 @@ use hyper_unix_connector::UnixConnector;
 use identity::IdentityService;
 use log::{error, info};
 use node::NodeService;
-use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    signal::unix::{signal, SignalKind},
+};
 use tonic::transport::Server;
 
-mod chroot;
+mod autogrow;
 mod config;
 mod controller;
+mod executor;
+mod fsck;
+mod gc;
+mod health;
 mod identity;
 mod logger;
+mod loopdev;
+mod mounts;
 mod node;
+mod paths;
 mod proto;
+mod reconcile;
+mod shrink;
 mod statfs;
 mod store;
+mod throttle;
+mod trash;
+mod trim;
+mod wipe;
 
 struct StreamWrapper(UnixConnector);
 
@@ -58,11 +74,22 @@ async fn main() {
         std::process::exit(1);
     }
 
-    if let Err(e) = chroot::create().await {
-        error!("failed to create chroot: {e:#}");
+    if let Err(e) = executor::init().await {
+        error!("failed to set up executor: {e:#}");
         std::process::exit(1);
     }
 
+    if let Err(e) = reconcile::reconcile().await {
+        error!("failed to reconcile volumes: {e:#}");
+    }
+
+    tokio::spawn(trash::run_purger());
+    tokio::spawn(gc::run());
+    tokio::spawn(health::run());
+    tokio::spawn(autogrow::run());
+    tokio::spawn(trim::run());
+    tokio::spawn(shrink::run());
+
     let service = Server::builder()
         .tcp_keepalive(Some(Duration::from_secs(5)))
         .max_concurrent_streams(50)
@@ -78,9 +105,23 @@ async fn main() {
     }
 
     service
-        .serve_with_incoming(StreamWrapper(
-            UnixListener::bind(&CONFIG.socket_path).unwrap().into(),
-        ))
+        .serve_with_incoming_shutdown(
+            StreamWrapper(UnixListener::bind(&CONFIG.socket_path).unwrap().into()),
+            shutdown_signal(),
+        )
         .await
         .unwrap();
+
+    if let Err(e) = executor::cleanup().await {
+        error!("failed to clean up executor: {e:#}");
+    }
+}
+
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
+    info!("shutting down");
 }
@@ -0,0 +1,376 @@
+use std::{
+    ffi::CString,
+    fmt, io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::prelude::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use anyhow::{Context, Result};
+use tonic::{Code, Status};
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// A single entry of `/proc/self/mountinfo`
+#[derive(Clone, Debug)]
+pub struct MountInfo {
+    /// `st_dev` of files within the mount
+    pub device: u64,
+    /// Directory of the filesystem mounted here, `/` unless it's a bind mount of a subdirectory
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub source: String,
+    /// Options of this mount alone, such as a bind's `ro`
+    pub options: Vec<String>,
+    /// Options of the filesystem itself, shared by every mount of it
+    pub super_options: Vec<String>,
+}
+
+// mountinfo escapes space, tab, newline and backslash as octal
+fn unescape(from: &str) -> String {
+    let mut out = String::with_capacity(from.len());
+    let mut chars = from.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        let octal: String = chars.by_ref().take(3).collect();
+        match u8::from_str_radix(&octal, 8) {
+            Ok(x) => out.push(x as char),
+            Err(_) => {
+                out.push('\\');
+                out.push_str(&octal);
+            }
+        }
+    }
+    out
+}
+
+fn parse_line(line: &str) -> Result<MountInfo> {
+    let (pre, post) = line.split_once(" - ").context("missing separator")?;
+    // skip mount id and parent id
+    let mut pre = pre.split(' ').skip(2);
+    let (major, minor) = pre
+        .next()
+        .and_then(|x| x.split_once(':'))
+        .context("missing device")?;
+    let device = libc::makedev(major.parse()?, minor.parse()?);
+    let root = unescape(pre.next().context("missing root")?);
+    let mount_point = unescape(pre.next().context("missing mount point")?);
+    let options = pre
+        .next()
+        .context("missing mount options")?
+        .split(',')
+        .map(str::to_string)
+        .collect();
+    let mut post = post.split(' ');
+    let fs_type = post.next().context("missing fs type")?.to_string();
+    let source = unescape(post.next().context("missing source")?);
+    let super_options = post
+        .next()
+        .context("missing super options")?
+        .split(',')
+        .map(str::to_string)
+        .collect();
+    Ok(MountInfo {
+        device,
+        root: root.into(),
+        mount_point: mount_point.into(),
+        fs_type,
+        source,
+        options,
+        super_options,
+    })
+}
+
+/// Lists the mounts visible to this process
+pub async fn mounts() -> Result<Vec<MountInfo>> {
+    let raw = tokio::fs::read_to_string(MOUNTINFO).await?;
+    raw.lines()
+        .filter(|x| !x.is_empty())
+        .map(|x| parse_line(x).with_context(|| format!("malformed mountinfo line '{x}'")))
+        .collect()
+}
+
+/// Finds the topmost mount at `target`, if any
+pub async fn find_mount(target: &Path) -> Result<Option<MountInfo>> {
+    Ok(mounts()
+        .await?
+        .into_iter()
+        .rev()
+        .find(|x| x.mount_point == target))
+}
+
+// linux/mount.h
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x4;
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// A failed mount syscall, keeping the errno for the CSI status
+#[derive(Debug)]
+pub struct MountError {
+    pub operation: &'static str,
+    pub source: Option<PathBuf>,
+    pub target: PathBuf,
+    pub error: io::Error,
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Some(source) => write!(
+                f,
+                "{} of '{}' on '{}' failed: {}",
+                self.operation,
+                source.display(),
+                self.target.display(),
+                self.error
+            ),
+            None => write!(
+                f,
+                "{} of '{}' failed: {}",
+                self.operation,
+                self.target.display(),
+                self.error
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MountError {}
+
+impl MountError {
+    pub fn to_status(&self, context: &str) -> Status {
+        let code = match self.error.raw_os_error() {
+            Some(libc::ENOENT) | Some(libc::ENOTDIR) => Code::NotFound,
+            Some(libc::EPERM) | Some(libc::EACCES) => Code::PermissionDenied,
+            Some(libc::EBUSY) => Code::FailedPrecondition,
+            _ => Code::Internal,
+        };
+        Status::new(code, format!("{context}: {self}"))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Propagation {
+    Private,
+    Slave,
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains NUL"))
+}
+
+fn check(ret: libc::c_long) -> io::Result<libc::c_long> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn mount_raw(
+    source: Option<&Path>,
+    target: &Path,
+    fs_type: Option<&str>,
+    flags: libc::c_ulong,
+    data: Option<&str>,
+) -> io::Result<()> {
+    let source = source.map(cstring).transpose()?;
+    let target = cstring(target)?;
+    let fs_type = fs_type.map(CString::new).transpose()?;
+    let data = data.map(CString::new).transpose()?;
+    check(unsafe {
+        libc::mount(
+            source.as_ref().map(|x| x.as_ptr()).unwrap_or(ptr::null()),
+            target.as_ptr(),
+            fs_type.as_ref().map(|x| x.as_ptr()).unwrap_or(ptr::null()),
+            flags,
+            data.as_ref()
+                .map(|x| x.as_ptr() as *const libc::c_void)
+                .unwrap_or(ptr::null()),
+        ) as libc::c_long
+    })?;
+    Ok(())
+}
+
+/// Read-only bind through the new mount API, so the mount never appears writable
+fn bind_new_api(source: &Path, target: &Path, readonly: bool, recursive: bool) -> io::Result<()> {
+    let source = cstring(source)?;
+    let target = cstring(target)?;
+    let recursive_flag = if recursive { libc::AT_RECURSIVE } else { 0 };
+    let tree = check(unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            source.as_ptr(),
+            libc::OPEN_TREE_CLONE | libc::OPEN_TREE_CLOEXEC | recursive_flag as libc::c_uint,
+        )
+    })?;
+    let tree = unsafe { OwnedFd::from_raw_fd(tree as RawFd) };
+    let empty = CString::default();
+    if readonly {
+        let attr = MountAttr {
+            attr_set: MOUNT_ATTR_RDONLY,
+            attr_clr: 0,
+            propagation: 0,
+            userns_fd: 0,
+        };
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_mount_setattr,
+                tree.as_raw_fd(),
+                empty.as_ptr(),
+                (libc::AT_EMPTY_PATH | recursive_flag) as libc::c_uint,
+                &attr as *const MountAttr,
+                std::mem::size_of::<MountAttr>(),
+            )
+        })?;
+    }
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            tree.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_FDCWD,
+            target.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        )
+    })?;
+    Ok(())
+}
+
+fn bind_blocking(source: &Path, target: &Path, readonly: bool, recursive: bool) -> io::Result<()> {
+    match bind_new_api(source, target, readonly, recursive) {
+        // open_tree needs 5.2, mount_setattr 5.12
+        Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => (),
+        x => return x,
+    }
+    let mut flags = libc::MS_BIND;
+    if recursive {
+        flags |= libc::MS_REC;
+    }
+    mount_raw(Some(source), target, None, flags, None)?;
+    if readonly {
+        mount_raw(
+            None,
+            target,
+            None,
+            libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+async fn blocking(
+    operation: &'static str,
+    source: Option<&Path>,
+    target: &Path,
+    f: impl FnOnce() -> io::Result<()> + Send + 'static,
+) -> Result<(), MountError> {
+    let result = tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+    result.map_err(|error| MountError {
+        operation,
+        source: source.map(Path::to_path_buf),
+        target: target.to_path_buf(),
+        error,
+    })
+}
+
+/// Mounts the filesystem on `source` at `target`
+pub async fn mount(
+    source: &Path,
+    target: &Path,
+    fs_type: &str,
+    readonly: bool,
+    data: Option<&str>,
+) -> Result<(), MountError> {
+    let flags = if readonly { libc::MS_RDONLY } else { 0 };
+    let (source_owned, target_owned) = (source.to_path_buf(), target.to_path_buf());
+    let (fs_type_owned, data_owned) = (fs_type.to_string(), data.map(str::to_string));
+    blocking("mount", Some(source), target, move || {
+        mount_raw(
+            Some(&source_owned),
+            &target_owned,
+            Some(&fs_type_owned),
+            flags,
+            data_owned.as_deref(),
+        )
+    })
+    .await
+}
+
+/// Bind mounts `source` at `target`, including mounts beneath it if `recursive`
+pub async fn bind(
+    source: &Path,
+    target: &Path,
+    readonly: bool,
+    recursive: bool,
+) -> Result<(), MountError> {
+    let (source_owned, target_owned) = (source.to_path_buf(), target.to_path_buf());
+    blocking("bind mount", Some(source), target, move || {
+        bind_blocking(&source_owned, &target_owned, readonly, recursive)
+    })
+    .await
+}
+
+/// Changes the options of an existing mount. Omitted flags are reset, so `readonly` has to be passed again.
+pub async fn remount(target: &Path, readonly: bool, data: Option<&str>) -> Result<(), MountError> {
+    let mut flags = libc::MS_REMOUNT;
+    if readonly {
+        flags |= libc::MS_RDONLY;
+    }
+    let target_owned = target.to_path_buf();
+    let data_owned = data.map(str::to_string);
+    blocking("remount", None, target, move || {
+        mount_raw(None, &target_owned, None, flags, data_owned.as_deref())
+    })
+    .await
+}
+
+pub async fn set_propagation(
+    target: &Path,
+    propagation: Propagation,
+    recursive: bool,
+) -> Result<(), MountError> {
+    let mut flags = match propagation {
+        Propagation::Private => libc::MS_PRIVATE,
+        Propagation::Slave => libc::MS_SLAVE,
+    };
+    if recursive {
+        flags |= libc::MS_REC;
+    }
+    let target_owned = target.to_path_buf();
+    blocking("propagation change", None, target, move || {
+        mount_raw(None, &target_owned, None, flags, None)
+    })
+    .await
+}
+
+/// Unmounts `target`, or with `lazy` detaches it now and lets it go once no longer busy
+pub async fn unmount(target: &Path, lazy: bool) -> Result<(), MountError> {
+    let flags = if lazy { libc::MNT_DETACH } else { 0 };
+    let target_owned = target.to_path_buf();
+    blocking("unmount", None, target, move || {
+        let target = cstring(&target_owned)?;
+        check(unsafe { libc::umount2(target.as_ptr(), flags) } as libc::c_long)?;
+        Ok(())
+    })
+    .await
+}
@@ -1,126 +1,364 @@
 use std::{
-    os::fd::AsRawFd,
-    path::{Path, PathBuf}, io::ErrorKind,
+    collections::HashMap,
+    fs::{File, Permissions},
+    io::ErrorKind,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
 };
 
 use crate::{
-    chroot::{run, run_in_chroot},
     config::{CONFIG, NODE},
-    controller::parse_volume_capability,
+    controller::{
+        adopt_volume, check_pool, parse_filesystem, parse_id, parse_size, parse_static_volume,
+        parse_volume_capability, remove_volume_data, resolve_pool, volume_condition,
+        volume_host_path, StaticVolume,
+    },
+    executor::{self, run, CommandError},
+    fsck, health,
+    loopdev::{self, LoopOptions},
+    mounts::{self, find_mount, MountError, MountInfo},
+    paths::VolumePath,
     proto::{
         node_server::Node, node_service_capability::rpc::Type as RpcType,
-        node_service_capability::Rpc, node_service_capability::Type as CapabilityType, *,
+        node_service_capability::Rpc, node_service_capability::Type as CapabilityType,
+        volume_capability::AccessType, *,
     },
-    store::{self, Filesystem, VolumeMode, VolumeState},
+    shrink,
+    store::{
+        self, Condition, Filesystem, FsckPolicy, VolumeConfig, VolumeCreation, VolumeMode,
+        VolumeState,
+    },
+    throttle, trim,
 };
-use anyhow::Result;
-use futures::TryFutureExt;
-use log::{error, info};
-use tokio::{fs::{OpenOptions, File}, process::Command};
+use anyhow::{Context, Result};
+use log::{error, info, warn};
 use tonic::{Request, Response, Status};
 
 #[derive(Debug)]
 pub struct NodeService {}
 
+const EPHEMERAL_CONTEXT: &str = "csi.storage.k8s.io/ephemeral";
+
+/// Surfaces mount errors with their errno and host tool failures with their stderr, anything else as a generic failure
+fn mount_failure(e: &anyhow::Error, message: &str) -> Status {
+    if let Some(e) = e.downcast_ref::<MountError>() {
+        return e.to_status(message);
+    }
+    match e.downcast_ref::<CommandError>() {
+        Some(e) => e.to_status(message),
+        None => Status::internal(message.to_string()),
+    }
+}
+
+async fn bind_mount(source: &Path, target: &Path, is_readonly: bool) -> Result<()> {
+    mounts::bind(source, target, is_readonly, false).await?;
+    Ok(())
+}
+
+fn fs_type(filesystem: Filesystem) -> &'static str {
+    match filesystem {
+        Filesystem::Xfs => "xfs",
+        _ => "ext4",
+    }
+}
+
 async fn mount_volume(
     loop_device: Option<&Path>,
-    source: &Path,
+    source: &VolumePath,
     target: &Path,
     is_readonly: bool,
     filesystem: Filesystem,
+    fsck: FsckPolicy,
+    options: LoopOptions,
 ) -> Result<Option<PathBuf>> {
     if filesystem == Filesystem::Bind {
-        let mut mount_args = vec!["mount", "bind"];
-        if is_readonly {
-            mount_args.push("-r");
-        }
-        mount_args.push(source.to_str().unwrap());
-        mount_args.push(target.to_str().unwrap());
-        run(&mount_args).await?;
+        bind_mount(&source.resolve().await?, target, is_readonly).await?;
         return Ok(None);
     }
-    let loop_device = if let Some(device) = loop_device {
-        device.to_path_buf()
+    // held until mounted, since the device detaches itself once unused
+    let (loop_device, _attached) = if let Some(device) = loop_device {
+        (device.to_path_buf(), None)
     } else {
-        let output =
-            run_in_chroot(&["losetup", "--show", "-L", "-f", source.to_str().unwrap()]).await?;
-        info!("losetup pipe: {output}");
-        // if !tokio::fs::try_exists(&pipe).await? {
-        //     return Err(std::io::Error::new(ErrorKind::Other, "failed to find pipe"));
-        // }
-        output.into()
+        let mut fsck = fsck;
+        let backing = match source.open(libc::O_RDWR).await {
+            // like losetup, fall back to a read-only device for read-only images
+            Err(e)
+                if is_readonly
+                    && matches!(e.raw_os_error(), Some(libc::EACCES) | Some(libc::EROFS)) =>
+            {
+                if fsck == FsckPolicy::Repair {
+                    fsck = FsckPolicy::Check;
+                }
+                source.open(libc::O_RDONLY).await?
+            }
+            x => x?,
+        };
+        let attached = loopdev::attach(backing, options).await?;
+        // only a fresh device is unmounted, and safe to check
+        fsck::check(&attached.path, filesystem, fsck).await?;
+        (attached.path.clone(), Some(attached))
     };
-    let mut mount_args = vec!["mount"];
-    if is_readonly {
-        mount_args.push("-r");
-    }
-    mount_args.push(loop_device.to_str().unwrap());
-    mount_args.push(target.to_str().unwrap());
-    run_in_chroot(&mount_args).await?;
+    mounts::mount(
+        &executor::device_path(&loop_device),
+        target,
+        fs_type(filesystem),
+        is_readonly,
+        None,
+    )
+    .await?;
     Ok(Some(loop_device))
 }
 
-async fn unloop_volume(target: &Path) -> Result<()> {
-    run_in_chroot(&["losetup", "-d", target.to_str().unwrap()]).await?;
+/// Mount options of a tmpfs volume, setting its root's ownership like [`make_volume`] does for the rest
+fn tmpfs_options(volume: &store::Volume) -> String {
+    let mut options = format!("size={}", volume.size);
+    if let Some(uid) = volume.uid {
+        options.push_str(&format!(",uid={uid}"));
+    }
+    if let Some(gid) = volume.gid {
+        options.push_str(&format!(",gid={gid}"));
+    }
+    if let Some(mode) = volume.mode {
+        options.push_str(&format!(",mode={mode:o}"));
+    }
+    options
+}
+
+async fn mount_tmpfs(
+    existing: Option<&Path>,
+    target: &Path,
+    options: &str,
+    is_readonly: bool,
+) -> Result<()> {
+    // every tmpfs mount is its own filesystem, so further publishes share the first one
+    if let Some(existing) = existing {
+        bind_mount(existing, target, is_readonly).await?;
+        return Ok(());
+    }
+    mounts::mount(
+        Path::new("tmpfs"),
+        target,
+        "tmpfs",
+        is_readonly,
+        Some(options),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn resize_tmpfs(target: &Path, size: u64) -> Result<()> {
+    // a remount resets flags, so keep the filesystem's own read-only state
+    let readonly = find_mount(target)
+        .await?
+        .map(|x| x.super_options.iter().any(|x| x == "ro"))
+        .unwrap_or_default();
+    mounts::remount(target, readonly, Some(&format!("size={size}"))).await?;
+    Ok(())
+}
+
+pub async fn unloop_volume(target: &Path) -> Result<()> {
+    loopdev::detach(target).await?;
     Ok(())
 }
 
 async fn unmount_volume(target: &Path) -> Result<()> {
-    run_in_chroot(&["umount", target.to_str().unwrap()]).await?;
+    mounts::unmount(target, false).await?;
+    Ok(())
+}
+
+/// What the kernel has mounted at a publish target
+enum TargetMount {
+    None,
+    /// The volume itself, through its loop device (given for images), tmpfs or bind source
+    Volume(Option<PathBuf>),
+    Foreign(MountInfo),
+}
+
+/// Looks up `target` in the kernel mount table, which is the truth when the store disagrees after a partial failure
+async fn target_mount(volume: &store::Volume, target: &Path) -> Result<TargetMount> {
+    let mounts = mounts::mounts().await?;
+    let find = |path: &Path| mounts.iter().rev().find(|x| x.mount_point == path);
+    let Some(mount) = find(target) else {
+        return Ok(TargetMount::None);
+    };
+    let mut loop_device = None;
+    let is_volume = match volume.filesystem {
+        // the recorded device may never have been saved, so go by the device's backing file
+        Filesystem::Ext4 | Filesystem::Xfs => {
+            let image = match VolumePath::of(volume)?.open(libc::O_PATH).await {
+                Ok(x) => Some(x.metadata()?),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+            if let (Some(device), Some(image)) = (loopdev::from_mount_source(&mount.source), image)
+            {
+                loop_device = loopdev::attached()
+                    .await?
+                    .into_iter()
+                    .find(|x| {
+                        x.path == device
+                            && x.backing_device == image.dev()
+                            && x.backing_inode == image.ino()
+                    })
+                    .map(|x| x.path);
+            }
+            loop_device.is_some()
+        }
+        // every publish shares the first tmpfs mount
+        Filesystem::Tmpfs => match volume
+            .mount_paths
+            .iter()
+            .filter(|x| *x != target)
+            .find_map(|x| find(x))
+        {
+            Some(other) => other.device == mount.device,
+            None => mount.fs_type == "tmpfs",
+        },
+        // any directory of the same filesystem shares its device, so the bind's root has to match too
+        Filesystem::Bind => {
+            let source_path = VolumePath::of(volume)?.resolve().await?;
+            let device = tokio::fs::metadata(&source_path).await?.dev();
+            let parent = mounts
+                .iter()
+                .filter(|x| x.device == device && source_path.starts_with(&x.mount_point))
+                .max_by_key(|x| x.mount_point.as_os_str().len());
+            match parent {
+                Some(parent) => {
+                    let root = parent
+                        .root
+                        .join(source_path.strip_prefix(&parent.mount_point)?);
+                    mount.device == device && mount.root == root
+                }
+                None => false,
+            }
+        }
+    };
+    Ok(if is_volume {
+        TargetMount::Volume(loop_device)
+    } else {
+        TargetMount::Foreign(mount.clone())
+    })
+}
+
+/// Grows the filesystem on a loop device to fill it
+async fn grow_filesystem(loop_device: &Path, filesystem: Filesystem) -> Result<()> {
+    match filesystem {
+        Filesystem::Ext4 => {
+            run(&["resize2fs", loop_device.to_str().unwrap()]).await?;
+        }
+        Filesystem::Xfs => {
+            run(&["xfs_growfs", "-d", loop_device.to_str().unwrap()]).await?;
+        }
+        Filesystem::Bind | Filesystem::Tmpfs => (),
+    }
     Ok(())
 }
 
 async fn expand_volume(
-    source_file: &Path,
+    source_file: &VolumePath,
     loop_device: &Path,
     size: u64,
     filesystem: Filesystem,
 ) -> Result<()> {
-    if filesystem == Filesystem::Bind {
+    if filesystem == Filesystem::Bind || filesystem == Filesystem::Tmpfs {
         return Ok(());
     }
     // expand source volume
-    let file = OpenOptions::new().write(true).open(source_file).await?;
+    source_file.set_len(size).await?;
+    // expand loop device
+    loopdev::set_capacity(loop_device).await?;
+    grow_filesystem(loop_device, filesystem).await
+}
+
+/// Loop device options a volume was provisioned with, applied on every attach
+pub fn loop_options(volume: &store::Volume) -> LoopOptions {
+    LoopOptions {
+        direct_io: volume.direct_io,
+        block_size: volume.logical_block_size,
+    }
+}
+
+/// Sets the owner and permissions of a volume's root directory, keeping whatever isn't given
+async fn set_ownership(
+    root: File,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mode: Option<u32>,
+) -> Result<()> {
     tokio::task::spawn_blocking(move || {
-        if unsafe { libc::ftruncate(file.as_raw_fd(), size as i64) } < 0 {
-            Err(std::io::Error::last_os_error())
-        } else {
-            Ok(())
+        if uid.is_some() || gid.is_some() {
+            // -1 leaves an id unchanged
+            let (uid, gid) = (uid.unwrap_or(u32::MAX), gid.unwrap_or(u32::MAX));
+            if unsafe { libc::fchown(root.as_raw_fd(), uid, gid) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        if let Some(mode) = mode {
+            root.set_permissions(Permissions::from_mode(mode))?;
         }
+        Ok(())
     })
     .await??;
-    // expand loop device
-    run_in_chroot(&["losetup", "-c", loop_device.to_str().unwrap()]).await?;
+    Ok(())
+}
 
-    // expand filesystem
-    match filesystem {
-        Filesystem::Ext4 => {
-            run(&["resize2fs", loop_device.to_str().unwrap()]).await?;
+/// Applies a volume's `uid`, `gid`, and `mode` to the root of its fresh filesystem, through a temporary mount
+async fn own_filesystem_root(volume_path: &VolumePath, volume: &store::Volume) -> Result<()> {
+    let backing = volume_path.open(libc::O_RDWR).await?;
+    // held until unmounted, since the device detaches itself once unused
+    let device = loopdev::attach(backing, loop_options(volume)).await?;
+    let mount_point = std::env::temp_dir().join(format!("lvp-mkfs-{}", volume.name));
+    tokio::fs::create_dir_all(&mount_point).await?;
+    mounts::mount(
+        &executor::device_path(&device.path),
+        &mount_point,
+        fs_type(volume.filesystem),
+        false,
+        None,
+    )
+    .await?;
+    let owned = match tokio::fs::File::open(&mount_point).await {
+        Ok(root) => set_ownership(root.into_std().await, volume.uid, volume.gid, volume.mode).await,
+        Err(e) => Err(e.into()),
+    };
+    mounts::unmount(&mount_point, false).await?;
+    drop(device);
+    if let Err(e) = tokio::fs::remove_dir(&mount_point).await {
+        warn!("failed to remove '{}': {e}", mount_point.display());
+    }
+    owned
+}
+
+/// Makes a volume's directory or formatted image. Anything left half made is removed again, as an existing
+/// volume is never made over.
+async fn make_volume(volume_path: &VolumePath, volume: &store::Volume) -> Result<()> {
+    let made = if volume.filesystem == Filesystem::Bind {
+        volume_path.create_dir().await?;
+        match volume_path.open(libc::O_RDONLY | libc::O_DIRECTORY).await {
+            Ok(root) => set_ownership(root, volume.uid, volume.gid, volume.mode).await,
+            Err(e) => Err(e.into()),
         }
-        Filesystem::Xfs => {
-            run(&["xfs_growfs", "-d", loop_device.to_str().unwrap()]).await?;
+    } else {
+        let file = volume_path.create_file().await?;
+        format_volume(file, volume_path, volume).await
+    };
+    if made.is_err() {
+        if let Err(e) = volume_path.remove().await {
+            error!(
+                "failed to remove partially made volume '{}': {e}",
+                volume_path.path().display()
+            );
         }
-        Filesystem::Bind => (),
     }
-
-    Ok(())
+    made
 }
 
-async fn make_volume(path: &Path, size: u64, filesystem: Filesystem) -> std::io::Result<()> {
-    if tokio::fs::try_exists(path).await? {
-        return Err(std::io::Error::new(
-            ErrorKind::AlreadyExists,
-            "volume file already exists",
-        ));
-    }
-    if filesystem == Filesystem::Bind {
-        tokio::fs::create_dir_all(path).await?;
-        return Ok(());
-    }
-    if let Some(parent) = path.parent() {
-        tokio::fs::create_dir_all(parent).await?;
-    }
-    let file = File::create(path).await?;
+async fn format_volume(file: File, volume_path: &VolumePath, volume: &store::Volume) -> Result<()> {
+    let (size, filesystem, logical_block_size) =
+        (volume.size, volume.filesystem, volume.logical_block_size);
     tokio::task::spawn_blocking(move || {
         if unsafe { libc::ftruncate(file.as_raw_fd(), size as i64) } < 0 {
             Err(std::io::Error::last_os_error())
@@ -129,28 +367,200 @@ async fn make_volume(path: &Path, size: u64, filesystem: Filesystem) -> std::io:
         }
     })
     .await??;
-    match filesystem {
-        Filesystem::Ext4 => {
-            let status = Command::new("mkfs.ext4").arg(path).spawn()?.wait().await?;
-            if !status.success() {
-                return Err(std::io::Error::new(
-                    ErrorKind::Other,
-                    &*format!("mkfs.ext4 exited with status {status}"),
-                ));
+    let path = executor::tool_path(&volume_path.path());
+    let path = path.to_str().context("volume path is not valid UTF-8")?;
+    // mkfs runs on the image file, so it can't see the sector size of the loop device
+    let sector_size = logical_block_size.map(|x| format!("size={x}"));
+    match (filesystem, &sector_size) {
+        // blocks can't be smaller than sectors, and 4k covers every supported size
+        (Filesystem::Ext4, Some(_)) => run(&["mkfs.ext4", "-b", "4096", path]).await?,
+        (Filesystem::Ext4, None) => run(&["mkfs.ext4", path]).await?,
+        (Filesystem::Xfs, Some(sector_size)) => run(&["mkfs.xfs", "-s", sector_size, path]).await?,
+        (Filesystem::Xfs, None) => run(&["mkfs.xfs", path]).await?,
+        (Filesystem::Bind | Filesystem::Tmpfs, _) => unreachable!(),
+    };
+    if volume.uid.is_some() || volume.gid.is_some() || volume.mode.is_some() {
+        own_filesystem_root(volume_path, volume).await?;
+    }
+
+    Ok(())
+}
+
+/// Hands a mounted volume's root to the group kubelet delegated `fsGroup` as, keeping new entries in it
+async fn apply_mount_group(target: &Path, gid: u32) -> Result<()> {
+    let root = tokio::fs::File::open(target).await?.into_std().await;
+    // group read, write, and search, with setgid so new entries inherit the group
+    let mode = root.metadata()?.permissions().mode() & 0o7777 | 0o2070;
+    set_ownership(root, None, Some(gid), Some(mode)).await
+}
+
+/// Registers an inline volume declared in a pod spec, sized and typed from its volume attributes
+async fn create_ephemeral_volume(
+    volume_id: &str,
+    volume_context: &HashMap<String, String>,
+    config: VolumeConfig,
+    requested_filesystem: Option<Filesystem>,
+) -> Result<store::Volume, Status> {
+    let mut pool = None::<&str>;
+    let mut host_base_path = None::<&str>;
+    let mut filesystem = requested_filesystem;
+    let mut size = 1073741824; // 1 GiB
+    for (name, value) in volume_context {
+        match &**name {
+            "pool" => pool = Some(value),
+            "host_base_path" => host_base_path = Some(value),
+            "fs_type" => {
+                let fs = parse_filesystem(value)?;
+                if filesystem.is_some() && filesystem != Some(fs) {
+                    return Err(Status::invalid_argument(
+                        "conflicting filesystems specified",
+                    ));
+                }
+                filesystem = Some(fs);
+            }
+            "size" => size = parse_size(value)?,
+            // pod info passed along by kubelet
+            _ if name.starts_with("csi.storage.k8s.io/") => (),
+            _ => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown volume attribute {name}"
+                )))
             }
         }
-        Filesystem::Xfs => {
-            let status = Command::new("mkfs.xfs").arg(path).spawn()?.wait().await?;
-            if !status.success() {
-                return Err(std::io::Error::new(
-                    ErrorKind::Other,
-                    &*format!("mkfs.xfs exited with status {status}"),
-                ));
+    }
+    let filesystem = filesystem.unwrap_or_default();
+
+    let pool = resolve_pool(pool, host_base_path)?;
+    if let Some((name, pool)) = pool {
+        check_pool(name, pool, filesystem, size, volume_id).await?;
+    }
+    let host_base_path = host_base_path.or_else(|| Some(&pool?.1.path));
+
+    let volume = store::Volume {
+        name: volume_id.to_string(),
+        size,
+        assigned_node_id: Some(NODE.clone()),
+        state: VolumeState::ControllerPublished,
+        published_readonly: false,
+        published_config: Some(config.clone()),
+        host_path: volume_host_path(host_base_path, volume_id, filesystem)?,
+        pool: pool.map(|(name, _)| name.clone()),
+        filesystem,
+        valid_configs: vec![config],
+        loop_device: None,
+        mount_paths: vec![],
+        ephemeral: true,
+        pvc_name: None,
+        pvc_namespace: None,
+        pv_name: None,
+        delete_policy: Default::default(),
+        wipe_on_delete: Default::default(),
+        wipe_offset: 0,
+        fsck: Default::default(),
+        condition: None,
+        needs_fs_resize: false,
+        allow_shrink: false,
+        refused_shrink: None,
+        autogrow: None,
+        direct_io: false,
+        logical_block_size: None,
+        io_limits: None,
+        throttled_device: None,
+        uid: None,
+        gid: None,
+        mode: None,
+        adopted: false,
+    };
+    let creation = volume.create().await.map_err(|e| {
+        error!("failed to save ephemeral volume: {e:#}");
+        Status::internal("internal failure")
+    })?;
+    match creation {
+        VolumeCreation::Success => Ok(volume),
+        VolumeCreation::AlreadyExists => match store::Volume::load(volume_id).await {
+            Ok(Some(x)) => Ok(x),
+            // race conditioned
+            Ok(None) => Err(Status::aborted("ephemeral volume concurrently deleted")),
+            Err(e) => {
+                error!("failed to load ephemeral volume: {e:#}");
+                Err(Status::internal("internal failure"))
             }
+        },
+    }
+}
+
+/// Mounts a static volume straight from its volume attributes, without tracking it in the store
+async fn publish_static_volume(
+    static_volume: &StaticVolume,
+    target: &Path,
+    is_readonly: bool,
+) -> Result<(), Status> {
+    match find_mount(target).await {
+        Ok(Some(_)) => return Ok(()),
+        Ok(None) => (),
+        Err(e) => {
+            error!("failed to read mounts: {e:#}");
+            return Err(Status::internal("internal failure"));
         }
-        Filesystem::Bind => unreachable!(),
     }
 
+    let volume_path = VolumePath::new(&static_volume.host_path, None)
+        .map_err(|e| Status::invalid_argument(format!("invalid host_path: {e}")))?;
+    let total_path = match volume_path.resolve().await {
+        Ok(x) => x,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(Status::not_found("host_path not found"))
+        }
+        Err(e) => {
+            error!(
+                "failed to resolve static volume '{}': {e}",
+                volume_path.path().display()
+            );
+            return Err(Status::invalid_argument(format!("invalid host_path: {e}")));
+        }
+    };
+
+    if let Err(e) = tokio::fs::create_dir_all(target).await {
+        error!(
+            "failed to create volume mountdir '{}': {e}",
+            target.display()
+        );
+        return Err(Status::internal("failed to create volume mountdir"));
+    }
+
+    if let Err(e) = mount_volume(
+        None,
+        &volume_path,
+        target,
+        is_readonly,
+        static_volume.filesystem,
+        FsckPolicy::None,
+        LoopOptions::default(),
+    )
+    .await
+    {
+        error!(
+            "failed to mount static volume '{}' to '{}': {e:#}",
+            total_path.display(),
+            target.display()
+        );
+        return Err(mount_failure(&e, "failed to mount volume"));
+    }
+    Ok(())
+}
+
+/// Unmounts a static volume that isn't tracked in the store, detaching its loop device if it has one
+async fn unpublish_static_volume(target: &Path) -> Result<()> {
+    let Some(mount) = find_mount(target).await? else {
+        return Ok(());
+    };
+    unmount_volume(target).await?;
+    if let Some(loop_device) = loopdev::from_mount_source(&mount.source) {
+        unloop_volume(&loop_device).await?;
+    }
+    if let Err(e) = tokio::fs::remove_dir(target).await {
+        error!("failed to delete target dir, ignoring: {e}");
+    }
     Ok(())
 }
 
@@ -189,15 +599,53 @@ impl Node for NodeService {
             return Err(Status::invalid_argument("missing target_path"));
         }
         let target: PathBuf = request.target_path.into();
+        let _shrink_hold = shrink::hold(&request.volume_id)
+            .ok_or_else(|| Status::unavailable("volume is being shrunk"))?;
 
         let Some(capability) = &request.volume_capability else {
             return Err(Status::invalid_argument("missing volume_capability"));
         };
         let (requested_config, requested_filesystem) = parse_volume_capability(capability)?;
+        let mount_group = match &capability.access_type {
+            Some(AccessType::Mount(x)) if !x.volume_mount_group.is_empty() => {
+                Some(parse_id("volume_mount_group", &x.volume_mount_group)?)
+            }
+            _ => None,
+        };
+        let is_ephemeral = request
+            .volume_context
+            .get(EPHEMERAL_CONTEXT)
+            .map(|x| x == "true")
+            .unwrap_or_default();
 
         let mut volume = match store::Volume::load(&request.volume_id).await {
             Ok(Some(x)) => x,
-            Ok(None) => return Err(Status::not_found("volume_id not found")),
+            Ok(None) if is_ephemeral => {
+                create_ephemeral_volume(
+                    &request.volume_id,
+                    &request.volume_context,
+                    requested_config.clone(),
+                    requested_filesystem,
+                )
+                .await?
+            }
+            Ok(None) => match parse_static_volume(&request.volume_context, requested_filesystem)? {
+                Some(static_volume) if static_volume.adopt => {
+                    adopt_volume(
+                        &request.volume_id,
+                        &NODE,
+                        &static_volume,
+                        requested_config.clone(),
+                        VolumeState::ControllerPublished,
+                    )
+                    .await?
+                }
+                Some(static_volume) => {
+                    publish_static_volume(&static_volume, &target, request.readonly).await?;
+                    return Ok(Response::new(NodePublishVolumeResponse {}));
+                }
+                None => return Err(Status::not_found("volume_id not found")),
+            },
             Err(e) => {
                 error!("failed to load volume for deletion: {e:#}");
                 return Err(Status::internal("internal failure"));
@@ -242,11 +690,41 @@ impl Node for NodeService {
                     "volume not published on controller",
                 ))
             }
+            VolumeState::Wiping => {
+                return Err(Status::failed_precondition("volume is being deleted"))
+            }
             VolumeState::ControllerPublished => (),
         }
 
-        if volume.mount_paths.contains(&target) {
-            return Ok(Response::new(NodePublishVolumeResponse {}));
+        let mounted = target_mount(&volume, &target).await.map_err(|e| {
+            error!("failed to read mounts for '{}': {e:#}", target.display());
+            Status::internal("failed to read mounts")
+        })?;
+        match mounted {
+            TargetMount::Volume(loop_device) => {
+                // mounted by an earlier attempt that failed before saving
+                let listed = volume.mount_paths.contains(&target);
+                if !listed || volume.loop_device.is_none() && loop_device.is_some() {
+                    volume.loop_device = volume.loop_device.take().or(loop_device);
+                    if !listed {
+                        volume.mount_paths.push(target);
+                    }
+                    volume.state = VolumeState::NodePublished;
+                    volume.update().await.map_err(|e| {
+                        error!("failed to update volume: {e:#}");
+                        Status::internal("failed to update volume")
+                    })?;
+                }
+                return Ok(Response::new(NodePublishVolumeResponse {}));
+            }
+            TargetMount::Foreign(mount) => {
+                return Err(Status::failed_precondition(format!(
+                    "target path already has '{}' mounted",
+                    mount.source
+                )));
+            }
+            // unmounted behind our back, mount it again
+            TargetMount::None => volume.mount_paths.retain(|x| x != &target),
         }
 
         if let Err(e) = tokio::fs::create_dir_all(&target).await {
@@ -257,56 +735,126 @@ impl Node for NodeService {
             return Err(Status::internal("failed to create volume mountdir"));
         }
 
-        let host_path = if volume.host_path.starts_with("/") {
-            &volume.host_path[1..]
+        let volume_path = if volume.filesystem == Filesystem::Tmpfs {
+            if volume.assigned_node_id.is_none() {
+                volume.assigned_node_id = Some(NODE.clone());
+                if let Err(e) = volume.update().await {
+                    error!("failed to save assigned_node_id: {e:#}");
+                    return Err(Status::internal("internal failure"));
+                }
+            }
+            None
         } else {
-            &volume.host_path
-        };
-        let total_path = CONFIG.host_prefix.join(host_path);
-
-        if !tokio::fs::try_exists(&total_path).map_err(|e| {
-            error!("failed to check volume existance: {e}");
-            Status::internal("failed to check volume existance")
-        }).await? {
-            info!("making new volume @ '{}'", total_path.display());
-            if let Err(e) = make_volume(&total_path, volume.size, volume.filesystem).await {
+            let volume_path = VolumePath::of(&volume).map_err(|e| {
+                error!("invalid path for volume {}: {e}", volume.name);
+                Status::internal("invalid volume path")
+            })?;
+            // refuses symlinks and mountpoints along the way
+            let exists = volume_path.exists().await.map_err(|e| {
                 error!(
-                    "failed to make new volume file: {e:#} @ {}",
-                    total_path.display()
+                    "failed to resolve volume '{}': {e}",
+                    volume_path.path().display()
                 );
-    
-                return Err(Status::internal("internal failure"));
-            }
-            volume.assigned_node_id = Some(NODE.clone());
-            if let Err(e) = volume.update().await {
-                error!("failed to save assigned_node_id: {e:#}");
-                return Err(Status::internal("internal failure"));
+                Status::internal("failed to resolve volume path")
+            })?;
+            if !exists {
+                info!("making new volume @ '{}'", volume_path.path().display());
+                if let Err(e) = make_volume(&volume_path, &volume).await {
+                    error!(
+                        "failed to make new volume file: {e:#} @ {}",
+                        volume_path.path().display()
+                    );
+                    return Err(mount_failure(&e, "failed to make volume"));
+                }
+                volume.assigned_node_id = Some(NODE.clone());
+                if let Err(e) = volume.update().await {
+                    error!("failed to save assigned_node_id: {e:#}");
+                    return Err(Status::internal("internal failure"));
+                }
             }
-        }
+            Some(volume_path)
+        };
 
-        let loop_device = match mount_volume(
-            volume.loop_device.as_deref(),
-            &total_path,
-            &target,
-            request.readonly || volume.published_readonly,
-            volume.filesystem,
-        )
-        .await
-        {
+        let is_readonly = request.readonly || volume.published_readonly;
+        let mounted = match &volume_path {
+            None => mount_tmpfs(
+                volume.mount_paths.first().map(|x| &**x),
+                &target,
+                &tmpfs_options(&volume),
+                is_readonly,
+            )
+            .await
+            .map(|_| None),
+            Some(volume_path) => {
+                mount_volume(
+                    volume.loop_device.as_deref(),
+                    volume_path,
+                    &target,
+                    is_readonly,
+                    volume.filesystem,
+                    volume.fsck,
+                    loop_options(&volume),
+                )
+                .await
+            }
+        };
+        let loop_device = match mounted {
             Ok(x) => x,
+            Err(e) if e.is::<fsck::Corrupt>() => {
+                error!("refusing to mount volume {}: {e:#}", volume.name);
+                volume.condition = Some(Condition {
+                    abnormal: true,
+                    message: e.to_string(),
+                });
+                if let Err(e) = volume.update().await {
+                    error!("failed to save volume condition: {e:#}");
+                }
+                return Err(Status::failed_precondition(e.to_string()));
+            }
             Err(e) => {
                 error!(
-                    "failed to mount volume '{}' to '{}': {e}",
-                    total_path.display(),
+                    "failed to mount volume '{}' to '{}': {e:#}",
+                    volume_path.map(|x| x.path()).unwrap_or_default().display(),
                     target.display()
                 );
-                return Err(Status::internal("failed to mount volume"));
+                return Err(mount_failure(&e, "failed to mount volume"));
             }
         };
 
         if volume.loop_device.is_none() && loop_device.is_some() {
             volume.loop_device = loop_device;
         }
+        // adopted volumes keep whatever ownership their host path had
+        if let (Some(gid), false, false) = (mount_group, is_readonly, volume.adopted) {
+            if let Err(e) = apply_mount_group(&target, gid).await {
+                error!(
+                    "failed to apply volume_mount_group to volume {}: {e:#}",
+                    volume.name
+                );
+            }
+        }
+        if let Err(e) = throttle::apply(&mut volume, &target).await {
+            error!(
+                "failed to apply I/O limits of volume {}: {e:#}",
+                volume.name
+            );
+        }
+        if let (true, Some(loop_device)) = (volume.needs_fs_resize, volume.loop_device.clone()) {
+            // a freshly attached loop device already spans the grown image
+            match grow_filesystem(&loop_device, volume.filesystem).await {
+                Ok(()) => volume.needs_fs_resize = false,
+                Err(e) => error!(
+                    "failed to grow filesystem of volume {}, retrying on next publish: {e:#}",
+                    volume.name
+                ),
+            }
+        }
+        // freshly mounted, the health monitor picks up any problems from here on
+        if volume.mount_paths.is_empty() {
+            volume.condition = None;
+            // its data may change, so a refused shrink can be tried again
+            volume.refused_shrink = None;
+        }
         volume.mount_paths.push(target);
 
         volume.state = VolumeState::NodePublished;
@@ -331,30 +879,78 @@ impl Node for NodeService {
             return Err(Status::invalid_argument("missing target_path"));
         }
 
+        let target: PathBuf = request.target_path.into();
+
         let mut volume = match store::Volume::load(&request.volume_id).await {
             Ok(Some(x)) => x,
-            Ok(None) => return Err(Status::not_found("volume_id not found")),
+            Ok(None) => {
+                if let Err(e) = unpublish_static_volume(&target).await {
+                    error!(
+                        "failed to unmount static volume '{}': {e:#}",
+                        target.display()
+                    );
+                    return Err(mount_failure(&e, "failed to unmount volume"));
+                }
+                return Ok(Response::new(NodeUnpublishVolumeResponse {}));
+            }
             Err(e) => {
                 error!("failed to load volume for deletion: {e:#}");
                 return Err(Status::internal("internal failure"));
             }
         };
 
-        let target: PathBuf = request.target_path.into();
-        if !matches!(volume.state, VolumeState::NodePublished)
-            || !volume.mount_paths.contains(&target)
-        {
-            return Ok(Response::new(NodeUnpublishVolumeResponse {}));
+        let listed = matches!(volume.state, VolumeState::NodePublished)
+            && volume.mount_paths.contains(&target);
+
+        // give space freed in the image back to the host while it's still mounted
+        if CONFIG.trim.on_unpublish && listed && volume.mount_paths.len() == 1 {
+            if let Err(e) = trim::trim_volume(&volume, &target).await {
+                warn!(
+                    "failed to trim volume {} before unmounting: {e:#}",
+                    volume.name
+                );
+            }
         }
 
-        if let Err(e) = unmount_volume(&target).await {
-            error!("failed to unmount volume '{}': {e}", target.display());
-            return Err(Status::internal("failed to unmount volume"));
+        // unmount whatever of ours the kernel still has, including mounts the store never recorded
+        let mut unmounted = false;
+        loop {
+            let mounted = target_mount(&volume, &target).await.map_err(|e| {
+                error!("failed to read mounts for '{}': {e:#}", target.display());
+                Status::internal("failed to read mounts")
+            })?;
+            match mounted {
+                TargetMount::None => break,
+                TargetMount::Foreign(_) if !listed || unmounted => break,
+                TargetMount::Foreign(mount) => warn!(
+                    "unmounting '{}' from '{}', expected volume {}",
+                    mount.source,
+                    target.display(),
+                    volume.name
+                ),
+                TargetMount::Volume(_) => (),
+            }
+            if let Err(e) = unmount_volume(&target).await {
+                error!("failed to unmount volume '{}': {e:#}", target.display());
+                return Err(mount_failure(&e, "failed to unmount volume"));
+            }
+            unmounted = true;
+        }
+        // an ephemeral volume forgotten by a reboot's reconcile still has to be cleaned up
+        let orphaned_ephemeral = volume.ephemeral && volume.mount_paths.is_empty();
+        if !listed && !unmounted && !orphaned_ephemeral {
+            return Ok(Response::new(NodeUnpublishVolumeResponse {}));
         }
 
+        if let Err(e) = throttle::remove(&volume, &target).await {
+            warn!("failed to lift I/O limits of volume {}: {e:#}", volume.name);
+        }
         volume.mount_paths.retain(|x| x != &target);
         if volume.mount_paths.is_empty() {
-            volume.state = VolumeState::ControllerPublished;
+            volume.throttled_device = None;
+            if volume.state == VolumeState::NodePublished {
+                volume.state = VolumeState::ControllerPublished;
+            }
             if let Some(loop_device) = volume.loop_device.take() {
                 if let Err(e) = unloop_volume(&loop_device).await {
                     error!("failed to unloop volume '{}': {e}", target.display());
@@ -362,10 +958,21 @@ impl Node for NodeService {
                 }
             }
         }
-        volume.update().await.map_err(|e| {
-            error!("failed to save volume: {e:#}");
-            Status::internal("failed to save volume")
-        })?;
+        if volume.ephemeral && volume.mount_paths.is_empty() {
+            if let Err(e) = remove_volume_data(&volume).await {
+                error!("failed to delete ephemeral volume data: {e:#}");
+                return Err(Status::internal("failed to delete ephemeral volume"));
+            }
+            volume.delete().await.map_err(|e| {
+                error!("failed to delete ephemeral volume: {e:#}");
+                Status::internal("failed to delete ephemeral volume")
+            })?;
+        } else {
+            volume.update().await.map_err(|e| {
+                error!("failed to save volume: {e:#}");
+                Status::internal("failed to save volume")
+            })?;
+        }
         if let Err(e) = tokio::fs::remove_dir(&target).await {
             error!("failed to delete target dir, ignoring: {e}");
         }
@@ -386,23 +993,48 @@ impl Node for NodeService {
             return Err(Status::invalid_argument("volume_path not found"));
         }
 
-        let volume = match store::Volume::load(&request.volume_id).await {
-            Ok(Some(x)) => x,
-            Ok(None) => return Err(Status::not_found("volume_id not found")),
+        let volume_path: PathBuf = request.volume_path.into();
+
+        let condition = match store::Volume::load(&request.volume_id).await {
+            Ok(Some(volume)) => {
+                if !matches!(volume.state, VolumeState::NodePublished)
+                    || !volume.mount_paths.contains(&volume_path)
+                {
+                    return Err(Status::not_found("volume path and id not found"));
+                }
+                health::volume_condition(&volume).await.map_err(|e| {
+                    error!("failed to check volume health: {e:#}");
+                    Status::internal("internal failure")
+                })?
+            }
+            // static volumes that lvp doesn't track are only known by their mount
+            Ok(None) => match find_mount(&volume_path).await {
+                Ok(Some(_)) => {
+                    let read_only =
+                        health::remounted_read_only(&volume_path)
+                            .await
+                            .map_err(|e| {
+                                error!("failed to read mounts: {e:#}");
+                                Status::internal("internal failure")
+                            })?;
+                    read_only.then(|| Condition {
+                        abnormal: true,
+                        message: "filesystem was remounted read-only, likely after errors"
+                            .to_string(),
+                    })
+                }
+                Ok(None) => return Err(Status::not_found("volume_id not found")),
+                Err(e) => {
+                    error!("failed to read mounts: {e:#}");
+                    return Err(Status::internal("internal failure"));
+                }
+            },
             Err(e) => {
                 error!("failed to load volume for deletion: {e:#}");
                 return Err(Status::internal("internal failure"));
             }
         };
 
-        let volume_path: PathBuf = request.volume_path.into();
-
-        if !matches!(volume.state, VolumeState::NodePublished)
-            || !volume.mount_paths.contains(&volume_path)
-        {
-            return Err(Status::not_found("volume path and id not found"));
-        }
-
         match crate::statfs::statfs(&volume_path).await {
             Err(e) => {
                 error!(
@@ -414,10 +1046,9 @@ impl Node for NodeService {
             Ok(stats) => Ok(Response::new(NodeGetVolumeStatsResponse {
                 usage: vec![
                     VolumeUsage {
-                        available: (stats.blocks_free_unprivileged * stats.block_size) as i64,
-                        total: (stats.block_count * stats.block_size) as i64,
-                        used: ((stats.block_count - stats.blocks_free_unprivileged)
-                            * stats.block_size) as i64,
+                        available: stats.available_bytes() as i64,
+                        total: stats.total_bytes() as i64,
+                        used: stats.used_bytes() as i64,
                         unit: volume_usage::Unit::Bytes as i32,
                     },
                     VolumeUsage {
@@ -427,10 +1058,7 @@ impl Node for NodeService {
                         unit: volume_usage::Unit::Inodes as i32,
                     },
                 ],
-                volume_condition: Some(VolumeCondition {
-                    abnormal: false,
-                    message: String::new(),
-                }),
+                volume_condition: Some(volume_condition(condition)),
             })),
         }
     }
@@ -469,30 +1097,43 @@ impl Node for NodeService {
             None => 1073741824, // 1 GiB
             Some(capacity) => capacity.required_bytes as u64,
         };
-        if target_capacity <= volume.size {
+        // grown offline, but the filesystem wasn't grown along with it yet
+        if target_capacity <= volume.size && !volume.needs_fs_resize {
             return Ok(Response::new(NodeExpandVolumeResponse {
                 capacity_bytes: volume.size as i64,
             }));
         }
 
-        let Some(loop_device) = &volume.loop_device else {
-            return Err(Status::not_found("loop device not found"));
-        };
+        if volume.filesystem == Filesystem::Tmpfs {
+            // bind mounts of the first publish share its superblock, so one remount covers all
+            if let Err(e) = resize_tmpfs(&volume_path, target_capacity).await {
+                error!("failed to resize tmpfs volume: {e:#}");
+                return Err(mount_failure(&e, "failed to resize volume"));
+            }
+        } else {
+            let Some(loop_device) = &volume.loop_device else {
+                return Err(Status::not_found("loop device not found"));
+            };
 
-        let total_path = CONFIG.host_prefix.join(&volume.host_path);
-        if let Err(e) = expand_volume(
-            &total_path,
-            &loop_device,
-            target_capacity,
-            volume.filesystem,
-        )
-        .await
-        {
-            error!("failed to resize volume: {e}");
-            return Err(Status::internal("failed to resize volume"));
+            let volume_path = VolumePath::of(&volume).map_err(|e| {
+                error!("invalid path for volume {}: {e}", volume.name);
+                Status::internal("invalid volume path")
+            })?;
+            if let Err(e) = expand_volume(
+                &volume_path,
+                &loop_device,
+                target_capacity.max(volume.size),
+                volume.filesystem,
+            )
+            .await
+            {
+                error!("failed to resize volume: {e:#}");
+                return Err(mount_failure(&e, "failed to resize volume"));
+            }
         }
 
-        volume.size = target_capacity;
+        volume.size = target_capacity.max(volume.size);
+        volume.needs_fs_resize = false;
         volume.update().await.map_err(|e| {
             error!("failed to save volume: {e:#}");
             Status::internal("failed to save volume")
@@ -524,6 +1165,16 @@ impl Node for NodeService {
                         r#type: RpcType::GetVolumeStats as i32,
                     })),
                 },
+                NodeServiceCapability {
+                    r#type: Some(CapabilityType::Rpc(Rpc {
+                        r#type: RpcType::VolumeCondition as i32,
+                    })),
+                },
+                NodeServiceCapability {
+                    r#type: Some(CapabilityType::Rpc(Rpc {
+                        r#type: RpcType::VolumeMountGroup as i32,
+                    })),
+                },
             ],
         }))
     }
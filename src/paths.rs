@@ -0,0 +1,473 @@
+use std::{
+    ffi::{CStr, CString},
+    fs::File,
+    io::{Error, ErrorKind, Result},
+    os::{
+        fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+        unix::prelude::OsStrExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{Pool, PoolBackend, CONFIG},
+    store,
+};
+
+// linux/openat2.h, libc's open_how is non_exhaustive
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+/// Directory at the root of each pool that archived volumes are moved into
+pub const TRASH_DIR: &str = ".lvp-trash";
+
+const RESOLVE_FLAGS: u64 = libc::RESOLVE_BENEATH
+    | libc::RESOLVE_NO_SYMLINKS
+    | libc::RESOLVE_NO_MAGICLINKS
+    | libc::RESOLVE_NO_XDEV;
+
+/// Normalizes a path relative to `host_prefix` (or a pool), refusing components that could step outside of it.
+/// Leading and trailing slashes are ignored, and an empty path is returned as-is.
+pub fn normalize(path: &str) -> Result<PathBuf> {
+    let mut out = PathBuf::new();
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        return Ok(out);
+    }
+    for component in trimmed.split('/') {
+        if component.is_empty() || component == "." || component == ".." || component.contains('\0')
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid path '{path}'"),
+            ));
+        }
+        out.push(component);
+    }
+    Ok(out)
+}
+
+fn cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "path contains NUL"))
+}
+
+fn check(ret: libc::c_int) -> Result<libc::c_int> {
+    if ret < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn fstat(fd: RawFd) -> Result<libc::stat64> {
+    let mut out: libc::stat64 = unsafe { std::mem::zeroed() };
+    check(unsafe { libc::fstat64(fd, &mut out) })?;
+    Ok(out)
+}
+
+fn fstatat(dir: RawFd, name: &CStr) -> Result<libc::stat64> {
+    let mut out: libc::stat64 = unsafe { std::mem::zeroed() };
+    check(unsafe { libc::fstatat64(dir, name.as_ptr(), &mut out, libc::AT_SYMLINK_NOFOLLOW) })?;
+    Ok(out)
+}
+
+fn openat(dir: RawFd, name: &CStr, flags: libc::c_int, mode: libc::mode_t) -> Result<OwnedFd> {
+    let fd = check(unsafe { libc::openat(dir, name.as_ptr(), flags | libc::O_CLOEXEC, mode) })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Refuses symlinks, and anything on another device than the root (a mountpoint)
+fn check_entry(stat: &libc::stat64, root_dev: libc::dev_t) -> Result<()> {
+    if stat.st_mode & libc::S_IFMT == libc::S_IFLNK {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "symlinks are not allowed in volume paths",
+        ));
+    }
+    if stat.st_dev != root_dev {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "mountpoints are not allowed in volume paths",
+        ));
+    }
+    Ok(())
+}
+
+/// Opens `relative` beneath `root` without following symlinks or crossing mountpoints
+fn open_beneath(
+    root: &OwnedFd,
+    relative: &Path,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+) -> Result<OwnedFd> {
+    let path = cstring(relative)?;
+    let how = OpenHow {
+        flags: (flags | libc::O_CLOEXEC) as u64,
+        mode: mode as u64,
+        resolve: RESOLVE_FLAGS,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root.as_raw_fd(),
+            path.as_ptr(),
+            &how as *const OpenHow,
+            std::mem::size_of::<OpenHow>(),
+        )
+    };
+    if fd >= 0 {
+        return Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) });
+    }
+    let error = Error::last_os_error();
+    if error.raw_os_error() != Some(libc::ENOSYS) {
+        return Err(error);
+    }
+
+    // kernels before 5.6 lack openat2, so walk one component at a time instead
+    let root_dev = fstat(root.as_raw_fd())?.st_dev;
+    let mut current = root.try_clone()?;
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        let name = cstring(Path::new(component.as_os_str()))?;
+        let is_last = components.peek().is_none();
+        let next = if is_last {
+            openat(current.as_raw_fd(), &name, flags | libc::O_NOFOLLOW, mode)?
+        } else {
+            openat(
+                current.as_raw_fd(),
+                &name,
+                libc::O_PATH | libc::O_NOFOLLOW,
+                0,
+            )?
+        };
+        check_entry(&fstat(next.as_raw_fd())?, root_dev)?;
+        current = next;
+    }
+    Ok(current)
+}
+
+fn remove_dir_all_at(parent: RawFd, name: &CStr, root_dev: libc::dev_t) -> Result<()> {
+    let fd = openat(
+        parent,
+        name,
+        libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+        0,
+    )?;
+    check_entry(&fstat(fd.as_raw_fd())?, root_dev)?;
+    let dir = unsafe { libc::fdopendir(fd.as_raw_fd()) };
+    if dir.is_null() {
+        return Err(Error::last_os_error());
+    }
+    // the DIR now owns the descriptor and closes it
+    let dir_fd = fd.into_raw_fd();
+
+    let result = (|| -> Result<()> {
+        loop {
+            let entry = unsafe { libc::readdir64(dir) };
+            if entry.is_null() {
+                return Ok(());
+            }
+            let entry_name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+            if entry_name.to_bytes() == b"." || entry_name.to_bytes() == b".." {
+                continue;
+            }
+            let stat = fstatat(dir_fd, entry_name)?;
+            if stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                remove_dir_all_at(dir_fd, entry_name, root_dev)?;
+            } else {
+                // symlinks are unlinked themselves, never followed
+                check(unsafe { libc::unlinkat(dir_fd, entry_name.as_ptr(), 0) })?;
+            }
+        }
+    })();
+    unsafe { libc::closedir(dir) };
+    result?;
+
+    check(unsafe { libc::unlinkat(parent, name.as_ptr(), libc::AT_REMOVEDIR) })?;
+    Ok(())
+}
+
+/// Location of a volume's data on the host. Everything below `root` is resolved without following symlinks
+/// or crossing mountpoints, so a planted symlink can never redirect volume operations elsewhere on the host.
+#[derive(Clone, Debug)]
+pub struct VolumePath {
+    /// Trusted directory: `host_prefix`, or the path of the pool holding the volume within it
+    root: PathBuf,
+    relative: PathBuf,
+}
+
+impl VolumePath {
+    pub fn new(host_path: &str, pool: Option<&str>) -> Result<Self> {
+        let host_path = normalize(host_path)?;
+        if host_path.as_os_str().is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "empty volume path"));
+        }
+        let beneath = |pool: &Pool| {
+            let pool_path = normalize(&pool.path).ok()?;
+            let relative = host_path.strip_prefix(&pool_path).ok()?;
+            (!relative.as_os_str().is_empty()).then(|| Self {
+                root: CONFIG.host_prefix.join(&pool_path),
+                relative: relative.to_path_buf(),
+            })
+        };
+        if let Some(path) = pool.and_then(|x| CONFIG.pools.get(x)).and_then(beneath) {
+            return Ok(path);
+        }
+        // volumes from before pools, and static paths, may still be on a pool's own disk
+        let containing = CONFIG
+            .pools
+            .values()
+            .filter(|x| x.backend == PoolBackend::Disk)
+            .filter_map(beneath)
+            .min_by_key(|x| x.relative.components().count());
+        if let Some(path) = containing {
+            return Ok(path);
+        }
+        Ok(Self {
+            root: CONFIG.host_prefix.clone(),
+            relative: host_path,
+        })
+    }
+
+    pub fn of(volume: &store::Volume) -> Result<Self> {
+        Self::new(&volume.host_path, volume.pool.as_deref())
+    }
+
+    /// A path beneath an arbitrary trusted root
+    pub fn beneath(root: &Path, relative: PathBuf) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            relative,
+        }
+    }
+
+    /// An entry of the trash directory beneath `root`
+    pub fn trash_entry(root: &Path, name: &str) -> Result<Self> {
+        let name = normalize(name)?;
+        if name.components().count() != 1 {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid trash entry"));
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+            relative: Path::new(TRASH_DIR).join(name),
+        })
+    }
+
+    /// Directories that may hold a trash directory: `host_prefix` and every disk pool
+    pub fn roots() -> Vec<PathBuf> {
+        let mut out = vec![CONFIG.host_prefix.clone()];
+        for pool in CONFIG.pools.values() {
+            if pool.backend != PoolBackend::Disk {
+                continue;
+            }
+            if let Ok(path) = normalize(&pool.path) {
+                let root = CONFIG.host_prefix.join(path);
+                if !out.contains(&root) {
+                    out.push(root);
+                }
+            }
+        }
+        out
+    }
+
+    /// Full path in this container, for display and external tools. Use [`VolumePath::resolve`] to validate it first.
+    pub fn path(&self) -> PathBuf {
+        self.root.join(&self.relative)
+    }
+
+    fn open_root(&self) -> Result<OwnedFd> {
+        openat(
+            libc::AT_FDCWD,
+            &cstring(&self.root)?,
+            libc::O_PATH | libc::O_DIRECTORY,
+            0,
+        )
+    }
+
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Self, OwnedFd) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || f(&this, this.open_root()?))
+            .await
+            .map_err(Error::other)?
+    }
+
+    /// Checks that the volume exists beneath its root, returning its full path
+    pub async fn resolve(&self) -> Result<PathBuf> {
+        self.blocking(|this, root| {
+            open_beneath(&root, &this.relative, libc::O_PATH, 0)?;
+            Ok(this.path())
+        })
+        .await
+    }
+
+    pub async fn exists(&self) -> Result<bool> {
+        match self.resolve().await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn open(&self, flags: libc::c_int) -> Result<File> {
+        self.blocking(move |this, root| Ok(open_beneath(&root, &this.relative, flags, 0)?.into()))
+            .await
+    }
+
+    /// Grows or shrinks the volume's image file to `size` bytes
+    pub async fn set_len(&self, size: u64) -> Result<()> {
+        self.blocking(move |this, root| {
+            let file: File = open_beneath(&root, &this.relative, libc::O_WRONLY, 0)?.into();
+            file.set_len(size)
+        })
+        .await
+    }
+
+    /// Creates any missing parent directories, returning the parent of the volume and its own name
+    fn create_parents(&self, root: &OwnedFd) -> Result<(OwnedFd, CString)> {
+        let mut parent_fd = root.try_clone()?;
+        let mut parent = PathBuf::new();
+        if let Some(parents) = self.relative.parent() {
+            for component in parents.components() {
+                let name = cstring(Path::new(component.as_os_str()))?;
+                if let Err(e) =
+                    check(unsafe { libc::mkdirat(parent_fd.as_raw_fd(), name.as_ptr(), 0o755) })
+                {
+                    if e.kind() != ErrorKind::AlreadyExists {
+                        return Err(e);
+                    }
+                }
+                parent.push(component);
+                parent_fd = open_beneath(root, &parent, libc::O_PATH | libc::O_DIRECTORY, 0)?;
+            }
+        }
+        let name = cstring(Path::new(self.relative.file_name().unwrap_or_default()))?;
+        Ok((parent_fd, name))
+    }
+
+    /// Creates a new, empty image file, failing if anything already exists there
+    pub async fn create_file(&self) -> Result<File> {
+        self.blocking(|this, root| {
+            let (parent, name) = this.create_parents(&root)?;
+            Ok(openat(
+                parent.as_raw_fd(),
+                &name,
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR | libc::O_NOFOLLOW,
+                0o644,
+            )?
+            .into())
+        })
+        .await
+    }
+
+    /// Creates a new directory, failing if anything already exists there
+    pub async fn create_dir(&self) -> Result<()> {
+        self.blocking(|this, root| {
+            let (parent, name) = this.create_parents(&root)?;
+            check(unsafe { libc::mkdirat(parent.as_raw_fd(), name.as_ptr(), 0o755) })?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Opens the existing parent of the volume, returning it and the volume's own name
+    fn open_parent(&self, root: OwnedFd) -> Result<(OwnedFd, CString, libc::stat64)> {
+        let parent = match self.relative.parent() {
+            Some(x) if !x.as_os_str().is_empty() => {
+                open_beneath(&root, x, libc::O_PATH | libc::O_DIRECTORY, 0)?
+            }
+            _ => root,
+        };
+        let root_dev = fstat(parent.as_raw_fd())?.st_dev;
+        let name = cstring(Path::new(self.relative.file_name().unwrap_or_default()))?;
+        let stat = fstatat(parent.as_raw_fd(), &name)?;
+        check_entry(&stat, root_dev)?;
+        Ok((parent, name, stat))
+    }
+
+    /// Moves the volume's file or directory tree into the trash directory of its root as `name`,
+    /// returning its new location
+    pub async fn archive(&self, name: &str) -> Result<PathBuf> {
+        let target = Self::trash_entry(&self.root, name)?;
+        self.blocking(move |this, root| {
+            let trash_name = cstring(Path::new(TRASH_DIR))?;
+            if let Err(e) =
+                check(unsafe { libc::mkdirat(root.as_raw_fd(), trash_name.as_ptr(), 0o700) })
+            {
+                if e.kind() != ErrorKind::AlreadyExists {
+                    return Err(e);
+                }
+            }
+            let trash = open_beneath(
+                &root,
+                Path::new(TRASH_DIR),
+                libc::O_PATH | libc::O_DIRECTORY,
+                0,
+            )?;
+            let (parent, name, _) = this.open_parent(root)?;
+            let target_name = cstring(Path::new(target.relative.file_name().unwrap_or_default()))?;
+            check(unsafe {
+                libc::renameat2(
+                    parent.as_raw_fd(),
+                    name.as_ptr(),
+                    trash.as_raw_fd(),
+                    target_name.as_ptr(),
+                    libc::RENAME_NOREPLACE,
+                )
+            })?;
+            Ok(target.path())
+        })
+        .await
+    }
+
+    /// Removes up to `depth` of the volume's parent directories, deepest first, stopping at the first one
+    /// that still holds anything
+    pub async fn remove_empty_parents(&self, depth: usize) -> Result<()> {
+        self.blocking(move |this, root| {
+            for parent in this.relative.ancestors().skip(1).take(depth) {
+                if parent.as_os_str().is_empty() {
+                    break;
+                }
+                let dir = Self::beneath(&this.root, parent.to_path_buf());
+                let (parent, name, _) = match dir.open_parent(root.try_clone()?) {
+                    Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                    x => x?,
+                };
+                match check(unsafe {
+                    libc::unlinkat(parent.as_raw_fd(), name.as_ptr(), libc::AT_REMOVEDIR)
+                }) {
+                    Ok(_) => (),
+                    Err(e) if e.kind() == ErrorKind::NotFound => (),
+                    Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTEMPTY | libc::EEXIST)) => {
+                        break
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Deletes the volume's file or directory tree, never following symlinks or descending into mountpoints
+    pub async fn remove(&self) -> Result<()> {
+        self.blocking(|this, root| {
+            let (parent, name, stat) = this.open_parent(root)?;
+            let root_dev = stat.st_dev;
+            if stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                remove_dir_all_at(parent.as_raw_fd(), &name, root_dev)
+            } else {
+                check(unsafe { libc::unlinkat(parent.as_raw_fd(), name.as_ptr(), 0) })?;
+                Ok(())
+            }
+        })
+        .await
+    }
+}
@@ -0,0 +1,147 @@
+use std::{collections::HashSet, os::unix::fs::MetadataExt, path::Path};
+
+use anyhow::Result;
+use log::{error, info, warn};
+
+use crate::{
+    config::{Executor, CONFIG, NODE},
+    loopdev::{self, AttachedLoop},
+    mounts::{self, MountInfo},
+    node::unloop_volume,
+    paths::VolumePath,
+    store::{self, Filesystem, VolumeState},
+};
+
+/// `st_dev` and `st_ino` of a volume's image file
+async fn image_id(volume: &store::Volume) -> Option<(u64, u64)> {
+    if !matches!(volume.filesystem, Filesystem::Ext4 | Filesystem::Xfs) {
+        return None;
+    }
+    let file = VolumePath::of(volume).ok()?.open(libc::O_PATH).await.ok()?;
+    let metadata = file.metadata().ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Whether a loop device's backing file lies within lvp's host directory
+fn backed_by_lvp(attached: &AttachedLoop, images: &HashSet<(u64, u64)>) -> bool {
+    if images.contains(&(attached.backing_device, attached.backing_inode)) {
+        return true;
+    }
+    let backing_file = Path::new(&attached.backing_file);
+    let host_prefix = &CONFIG.host_prefix;
+    // devices attached from a chroot report the path within it
+    let chroot = match &CONFIG.executor {
+        Executor::Chroot { path, .. } => path.clone(),
+        Executor::HostNamespace => Executor::default_chroot_path(),
+    };
+    backing_file.starts_with(host_prefix)
+        || backing_file
+            .starts_with(chroot.join(host_prefix.strip_prefix("/").unwrap_or(host_prefix)))
+}
+
+async fn reconcile_volume(
+    volume: &mut store::Volume,
+    mounts: &[MountInfo],
+    loops: &[AttachedLoop],
+    image: Option<(u64, u64)>,
+) -> Result<()> {
+    let mut changed = false;
+
+    let before = volume.mount_paths.len();
+    volume.mount_paths.retain(|path| {
+        let mounted = mounts.iter().any(|x| &x.mount_point == path);
+        if !mounted {
+            warn!(
+                "volume {} is no longer mounted at '{}'",
+                volume.name,
+                path.display()
+            );
+        }
+        mounted
+    });
+    changed |= volume.mount_paths.len() != before;
+
+    if let Some(loop_device) = &volume.loop_device {
+        // after a reboot the device may be gone, or reused for something else entirely
+        let valid = loops
+            .iter()
+            .any(|x| &x.path == loop_device && Some((x.backing_device, x.backing_inode)) == image);
+        if !valid {
+            warn!(
+                "volume {} recorded stale loop device '{}'",
+                volume.name,
+                loop_device.display()
+            );
+            volume.loop_device = None;
+            changed = true;
+        }
+    }
+
+    if volume.mount_paths.is_empty() {
+        if volume.state == VolumeState::NodePublished {
+            volume.state = VolumeState::ControllerPublished;
+            changed = true;
+        }
+        if let Some(loop_device) = volume.loop_device.take() {
+            info!(
+                "detaching unused loop device '{}' of volume {}",
+                loop_device.display(),
+                volume.name
+            );
+            unloop_volume(&loop_device).await?;
+            changed = true;
+        }
+    }
+
+    if changed {
+        volume.update().await?;
+    }
+    Ok(())
+}
+
+/// Reconciles the volumes on this node with the kernel's mounts and loop devices, which don't survive a reboot,
+/// and detaches loop devices of lvp images that no volume claims
+pub async fn reconcile() -> Result<()> {
+    let mounts = mounts::mounts().await?;
+    let loops = loopdev::attached().await?;
+
+    let mut images = HashSet::new();
+    let mut claimed = HashSet::new();
+    for mut volume in store::Volume::list().await? {
+        if volume.assigned_node_id.as_deref() != Some(&**NODE) {
+            continue;
+        }
+        let image = image_id(&volume).await;
+        images.extend(image);
+        if let Err(e) = reconcile_volume(&mut volume, &mounts, &loops, image).await {
+            error!("failed to reconcile volume {}: {e:#}", volume.name);
+        }
+        claimed.extend(volume.loop_device);
+    }
+
+    for attached in &loops {
+        if claimed.contains(&attached.path) || !backed_by_lvp(attached, &images) {
+            continue;
+        }
+        if mounts
+            .iter()
+            .any(|x| loopdev::from_mount_source(&x.source).as_ref() == Some(&attached.path))
+        {
+            warn!(
+                "orphaned loop device '{}' for '{}' is still mounted, leaving it",
+                attached.path.display(),
+                attached.backing_file
+            );
+            continue;
+        }
+        info!(
+            "detaching orphaned loop device '{}' for '{}'",
+            attached.path.display(),
+            attached.backing_file
+        );
+        if let Err(e) = unloop_volume(&attached.path).await {
+            error!("failed to detach '{}': {e:#}", attached.path.display());
+        }
+    }
+    Ok(())
+}
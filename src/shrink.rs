@@ -0,0 +1,344 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use k8s_openapi::{
+    api::core::v1::{Event, EventSource, ObjectReference, PersistentVolume},
+    apimachinery::pkg::apis::meta::v1::Time,
+    chrono::Utc,
+};
+use kube::{
+    api::{ObjectMeta, Patch, PatchParams},
+    Api,
+};
+use log::{error, info, warn};
+use serde_json::json;
+use tokio::sync::{OwnedRwLockReadGuard, RwLock};
+
+use crate::{
+    config::{CONFIG, NODE},
+    controller::parse_size,
+    executor::{self, run_uninterrupted},
+    fsck::exit_code,
+    loopdev::{self, LoopOptions},
+    node::loop_options,
+    paths::VolumePath,
+    store::{self, Filesystem, VolumeState, CLIENT},
+};
+
+/// PV annotation requesting a smaller size for a volume provisioned with `allow_shrink`
+pub const SHRINK_ANNOTATION: &str = "lvp/shrink-to";
+/// PV annotation set to why a requested shrink was refused, so it is only reported once
+pub const REFUSED_ANNOTATION: &str = "lvp/shrink-refused";
+
+/// Share of the smallest possible size kept free, so a shrunk filesystem isn't left full
+const HEADROOM_PERCENT: u64 = 10;
+
+/// Per-volume locks, held exclusively while a volume is shrunk
+static LOCKS: Mutex<BTreeMap<String, Arc<RwLock<()>>>> = Mutex::new(BTreeMap::new());
+
+fn lock(name: &str) -> Arc<RwLock<()>> {
+    LOCKS
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default()
+        .clone()
+}
+
+/// Keeps a volume from being shrunk while it is published, expanded or deleted. Fails if a shrink is underway.
+pub fn hold(name: &str) -> Option<OwnedRwLockReadGuard<()>> {
+    lock(name).try_read_owned().ok()
+}
+
+/// A shrink that would cut into data
+#[derive(Debug)]
+pub struct TooSmall {
+    pub requested: u64,
+    pub minimum: u64,
+}
+
+impl fmt::Display for TooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "requested size {} is below the {} bytes the filesystem needs",
+            self.requested, self.minimum
+        )
+    }
+}
+
+impl std::error::Error for TooSmall {}
+
+fn annotation<'a>(pv: &'a PersistentVolume, name: &str) -> Option<&'a str> {
+    pv.metadata.annotations.as_ref()?.get(name).map(|x| &**x)
+}
+
+/// Size requested through the volume's PV annotation, if below its current size
+fn requested_size(volume: &store::Volume, pv: &PersistentVolume) -> Result<Option<u64>> {
+    let Some(requested) = annotation(pv, SHRINK_ANNOTATION) else {
+        return Ok(None);
+    };
+    let size = parse_size(requested).map_err(|e| {
+        anyhow::anyhow!("invalid {SHRINK_ANNOTATION} '{requested}': {}", e.message())
+    })?;
+    Ok((size < volume.size).then_some(size))
+}
+
+/// Records an event about a shrink on the PV, so it shows up in `kubectl describe`
+async fn record_event(
+    pv: &PersistentVolume,
+    type_: &str,
+    reason: &str,
+    message: &str,
+) -> Result<()> {
+    let name = pv.metadata.name.clone().unwrap_or_default();
+    // events of cluster-scoped objects live in the default namespace
+    let events: Api<Event> = Api::namespaced(CLIENT.clone(), "default");
+    let now = Time(Utc::now());
+    events
+        .create(
+            &Default::default(),
+            &Event {
+                metadata: ObjectMeta {
+                    generate_name: Some(format!("{name}.")),
+                    ..Default::default()
+                },
+                involved_object: ObjectReference {
+                    api_version: Some("v1".to_string()),
+                    kind: Some("PersistentVolume".to_string()),
+                    name: Some(name),
+                    uid: pv.metadata.uid.clone(),
+                    ..Default::default()
+                },
+                type_: Some(type_.to_string()),
+                reason: Some(reason.to_string()),
+                message: Some(message.to_string()),
+                source: Some(EventSource {
+                    component: Some("lvp".to_string()),
+                    host: Some(NODE.clone()),
+                }),
+                first_timestamp: Some(now.clone()),
+                last_timestamp: Some(now),
+                count: Some(1),
+                ..Default::default()
+            },
+        )
+        .await?;
+    Ok(())
+}
+
+async fn patch_pv(pv: &PersistentVolume, patch: serde_json::Value) -> Result<()> {
+    let pvs: Api<PersistentVolume> = Api::all(CLIENT.clone());
+    let name = pv.metadata.name.as_deref().unwrap_or_default();
+    pvs.patch(name, &PatchParams::default(), &Patch::Merge(patch))
+        .await?;
+    Ok(())
+}
+
+/// Reports a refused shrink on the PV, unless it was already refused for the same reason
+async fn refuse(pv: &PersistentVolume, reason: &str) -> Result<()> {
+    if annotation(pv, REFUSED_ANNOTATION) == Some(reason) {
+        return Ok(());
+    }
+    warn!(
+        "not shrinking PV {}: {reason}",
+        pv.metadata.name.as_deref().unwrap_or_default()
+    );
+    patch_pv(
+        pv,
+        json!({ "metadata": { "annotations": { REFUSED_ANNOTATION: reason } } }),
+    )
+    .await?;
+    record_event(pv, "Warning", "ShrinkRefused", reason).await
+}
+
+/// Reports the new size on the PV and clears the request. The PVC keeps its larger request and
+/// capacity, as Kubernetes can't lower a request and the resizer would grow it back to match.
+async fn report_shrunk(pv: &PersistentVolume, size: u64) -> Result<()> {
+    patch_pv(
+        pv,
+        json!({
+            "metadata": {
+                "annotations": {
+                    SHRINK_ANNOTATION: null,
+                    REFUSED_ANNOTATION: null,
+                },
+            },
+            "spec": {
+                "capacity": {
+                    "storage": size.to_string(),
+                },
+            },
+        }),
+    )
+    .await?;
+    record_event(pv, "Normal", "Shrunk", &format!("shrunk to {size} bytes")).await
+}
+
+/// Value of a `Name: value` line from `dumpe2fs -h`
+fn superblock_field(dump: &str, name: &str) -> Option<u64> {
+    dump.lines()
+        .find_map(|x| x.strip_prefix(name)?.strip_prefix(':'))
+        .and_then(|x| x.trim().parse().ok())
+}
+
+async fn shrink_filesystem(device: &Path, size: u64) -> Result<()> {
+    let path = device.to_str().unwrap_or_default();
+    // resize2fs refuses to shrink without a fresh forced check
+    let (code, output) = exit_code(run_uninterrupted(&["e2fsck", "-f", "-p", path]).await)?;
+    if code > 1 {
+        bail!("e2fsck failed on {path} with code {code}: {output}");
+    }
+
+    let dump = executor::run(&["dumpe2fs", "-h", path]).await?;
+    let block_size = superblock_field(&dump, "Block size").context("missing block size")?;
+    let estimate = executor::run(&["resize2fs", "-P", path]).await?;
+    let minimum_blocks: u64 = estimate
+        .lines()
+        .find_map(|x| x.strip_prefix("Estimated minimum size of the filesystem:"))
+        .and_then(|x| x.trim().parse().ok())
+        .context("missing minimum size estimate")?;
+    let minimum_blocks = minimum_blocks + minimum_blocks * HEADROOM_PERCENT / 100;
+    let blocks = size / block_size;
+    if blocks < minimum_blocks {
+        return Err(TooSmall {
+            requested: size,
+            minimum: minimum_blocks * block_size,
+        }
+        .into());
+    }
+
+    // killing resize2fs while it moves blocks would corrupt the filesystem
+    run_uninterrupted(&["resize2fs", path, &blocks.to_string()]).await?;
+    Ok(())
+}
+
+/// Shrinks the ext4 filesystem of an unpublished volume to `size` bytes, then truncates its image
+pub async fn shrink(volume_path: &VolumePath, size: u64, options: LoopOptions) -> Result<()> {
+    let backing = volume_path.open(libc::O_RDWR).await?;
+    // the handle keeps the device attached until the filesystem is shrunk
+    let device = loopdev::attach(backing, options).await?;
+    shrink_filesystem(&device.path, size).await?;
+    // detaches itself once closed
+    drop(device);
+
+    volume_path.set_len(size).await?;
+    info!(
+        "shrunk image '{}' to {size} bytes",
+        volume_path.path().display()
+    );
+    Ok(())
+}
+
+/// Shrinks a volume to the size requested on its PV, remembering a refused size so it isn't tried again
+async fn shrink_volume(volume: &mut store::Volume, pv: &PersistentVolume) -> Result<()> {
+    let size = match requested_size(volume, pv) {
+        Ok(Some(x)) => x,
+        Ok(None) => return Ok(()),
+        Err(e) => return refuse(pv, &format!("{e:#}")).await,
+    };
+    // not retried until the volume is published again and its data may have changed
+    if volume.refused_shrink == Some(size) {
+        return Ok(());
+    }
+    info!(
+        "shrinking volume {} from {} to {size} bytes",
+        volume.name, volume.size
+    );
+    match shrink(&VolumePath::of(volume)?, size, loop_options(volume)).await {
+        Ok(()) => {
+            volume.size = size;
+            volume.refused_shrink = None;
+            volume.update().await?;
+            report_shrunk(pv, size).await
+        }
+        // nothing was changed, so keep serving it at its current size
+        Err(e) if e.is::<TooSmall>() => {
+            volume.refused_shrink = Some(size);
+            volume.update().await?;
+            refuse(pv, &e.to_string()).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn shrink_pass() -> Result<()> {
+    let pvs: Api<PersistentVolume> = Api::all(CLIENT.clone());
+    let pvs: BTreeMap<_, _> = pvs
+        .list(&Default::default())
+        .await?
+        .into_iter()
+        .filter_map(|x| Some((x.metadata.name.clone()?, x)))
+        .collect();
+    for volume in store::Volume::list().await? {
+        if volume.assigned_node_id.as_deref() != Some(&**NODE) {
+            continue;
+        }
+        let Some(pv) = volume.pv_name.as_ref().and_then(|x| pvs.get(x)) else {
+            continue;
+        };
+        if annotation(pv, SHRINK_ANNOTATION).is_none() {
+            continue;
+        }
+        if !volume.allow_shrink {
+            let reason = if volume.filesystem == Filesystem::Ext4 {
+                "volume wasn't provisioned with allow_shrink".to_string()
+            } else {
+                format!("{:?} volumes can't be shrunk, only ext4", volume.filesystem)
+            };
+            if let Err(e) = refuse(pv, &reason).await {
+                error!(
+                    "failed to report refused shrink of volume {}: {e:#}",
+                    volume.name
+                );
+            }
+            continue;
+        }
+        if !matches!(
+            volume.state,
+            VolumeState::Open | VolumeState::ControllerPublished
+        ) {
+            continue;
+        }
+        let Ok(_guard) = lock(&volume.name).try_write_owned() else {
+            continue;
+        };
+        // reloaded under the lock, as a publish may have finished in the meantime
+        let Some(mut volume) = store::Volume::load(&volume.name).await? else {
+            continue;
+        };
+        // only shrink while nothing has the filesystem mounted
+        if !matches!(
+            volume.state,
+            VolumeState::Open | VolumeState::ControllerPublished
+        ) || volume.loop_device.is_some()
+            || !volume.mount_paths.is_empty()
+        {
+            continue;
+        }
+        if let Err(e) = shrink_volume(&mut volume, pv).await {
+            error!("failed to shrink volume {}: {e:#}", volume.name);
+        }
+    }
+    Ok(())
+}
+
+/// Periodically shrinks unpublished volumes on this node to the size requested on their PV
+pub async fn run() {
+    if CONFIG.shrink.interval == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(CONFIG.shrink.interval);
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = shrink_pass().await {
+            error!("shrinking volumes failed: {e:#}");
+        }
+    }
+}
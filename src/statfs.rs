@@ -16,6 +16,22 @@ pub struct Stats {
     pub filename_max_len: u64,
 }
 
+impl Stats {
+    /// Bytes usable by unprivileged users
+    pub fn available_bytes(&self) -> u64 {
+        self.blocks_free_unprivileged * self.block_size
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.block_count * self.block_size
+    }
+
+    /// Bytes in use, counting those reserved for root
+    pub fn used_bytes(&self) -> u64 {
+        (self.block_count - self.blocks_free_unprivileged) * self.block_size
+    }
+}
+
 pub async fn statfs(path: &Path) -> std::io::Result<Stats> {
     let path: Vec<u8> = path.as_os_str().as_bytes().to_vec();
     tokio::task::spawn_blocking(move || {
@@ -1,7 +1,11 @@
+mod reservation;
+mod retained;
 mod volume;
 
 use always_cell::AlwaysCell;
 use kube::Client;
+pub use reservation::*;
+pub use retained::*;
 pub use volume::*;
 use anyhow::Result;
 
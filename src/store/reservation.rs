@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{core::ObjectMeta, Api};
+use serde::{Deserialize, Serialize};
+
+use crate::config::NAMESPACE;
+
+use super::CLIENT;
+
+/// Claim on a host path rendered from a `path_pattern`, so two volumes are never given the same one
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PathReservation {
+    pub host_path: String,
+    /// Directory the pattern was rendered beneath, directories above it weren't made for the volume
+    pub base_path: String,
+    pub pool: Option<String>,
+    pub volume: String,
+}
+
+/// Names can't hold arbitrary paths, so reservations are keyed on their FNV-1a hash
+fn key(host_path: &str) -> String {
+    let hash = host_path.bytes().fold(0xcbf29ce484222325u64, |hash, x| {
+        (hash ^ x as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("lvp-path-{hash:016x}")
+}
+
+fn parse(config: ConfigMap) -> Result<PathReservation> {
+    Ok(serde_json::from_str(
+        config
+            .data
+            .context("no data")?
+            .get("data.json")
+            .context("missing data.json")?,
+    )?)
+}
+
+impl PathReservation {
+    /// Reserves the path for the volume, returning false if another volume holds it. The reservation is
+    /// created atomically, so of two volumes racing for a path only one gets it.
+    pub async fn create(&self) -> Result<bool> {
+        let configs: Api<ConfigMap> = Api::default_namespaced(CLIENT.clone());
+        let mut data = BTreeMap::new();
+        data.insert("data.json".to_string(), serde_json::to_string(&self)?);
+        let created = configs
+            .create(
+                &Default::default(),
+                &ConfigMap {
+                    data: Some(data),
+                    metadata: ObjectMeta {
+                        name: Some(key(&self.host_path)),
+                        namespace: Some(NAMESPACE.clone()),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .await;
+        match created {
+            Ok(_) => Ok(true),
+            // a retried creation already holds it
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(Self::load(&self.host_path)
+                .await?
+                .map(|x| x.volume == self.volume && x.host_path == self.host_path)
+                .unwrap_or_default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn load(host_path: &str) -> Result<Option<Self>> {
+        let configs: Api<ConfigMap> = Api::default_namespaced(CLIENT.clone());
+        let Some(config) = configs.get_opt(&key(host_path)).await? else {
+            return Ok(None);
+        };
+        Ok(Some(parse(config)?))
+    }
+
+    pub async fn list() -> Result<Vec<Self>> {
+        let configs: Api<ConfigMap> = Api::default_namespaced(CLIENT.clone());
+        let mut out = vec![];
+        for config in configs.list(&Default::default()).await? {
+            if !config
+                .metadata
+                .name
+                .as_deref()
+                .unwrap_or_default()
+                .starts_with("lvp-path-")
+            {
+                continue;
+            }
+            out.push(parse(config)?);
+        }
+        Ok(out)
+    }
+
+    pub async fn delete(&self) -> Result<()> {
+        let configs: Api<ConfigMap> = Api::default_namespaced(CLIENT.clone());
+        match configs
+            .delete(&key(&self.host_path), &Default::default())
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(e)) if e.code == 404 => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{core::ObjectMeta, Api};
+use serde::{Deserialize, Serialize};
+
+use crate::config::NAMESPACE;
+
+use super::CLIENT;
+
+/// Data a deleted volume left on the host, recorded so it is never collected as orphaned
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Retained {
+    pub name: String,
+    pub host_path: String,
+    pub assigned_node_id: Option<String>,
+}
+
+impl Retained {
+    fn key(&self) -> String {
+        format!("lvp-retained-{}", self.name)
+    }
+
+    pub async fn create(&self) -> Result<()> {
+        let configs: Api<ConfigMap> = Api::default_namespaced(CLIENT.clone());
+        if configs.get_opt(&self.key()).await?.is_some() {
+            return Ok(());
+        }
+        let mut data = BTreeMap::new();
+        data.insert("data.json".to_string(), serde_json::to_string(&self)?);
+        configs
+            .create(
+                &Default::default(),
+                &ConfigMap {
+                    data: Some(data),
+                    metadata: ObjectMeta {
+                        name: Some(self.key()),
+                        namespace: Some(NAMESPACE.clone()),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list() -> Result<Vec<Self>> {
+        let configs: Api<ConfigMap> = Api::default_namespaced(CLIENT.clone());
+        let mut out = vec![];
+        for config in configs.list(&Default::default()).await? {
+            if !config
+                .metadata
+                .name
+                .as_deref()
+                .unwrap_or_default()
+                .starts_with("lvp-retained-")
+            {
+                continue;
+            }
+            out.push(serde_json::from_str(
+                config
+                    .data
+                    .context("no data")?
+                    .get("data.json")
+                    .context("missing data.json")?,
+            )?);
+        }
+        Ok(out)
+    }
+}
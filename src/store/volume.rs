@@ -23,6 +23,100 @@ pub struct Volume {
     pub loop_device: Option<PathBuf>,
     pub mount_paths: Vec<PathBuf>,
     pub host_path: String,
+    /// Name of the configured pool the volume was provisioned from
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// Inline volume owned by a single pod, destroyed on node unpublish
+    #[serde(default)]
+    pub ephemeral: bool,
+    /// Claim metadata passed by csi-provisioner with `--extra-create-metadata`
+    #[serde(default)]
+    pub pvc_name: Option<String>,
+    #[serde(default)]
+    pub pvc_namespace: Option<String>,
+    #[serde(default)]
+    pub pv_name: Option<String>,
+    /// What happens to the volume's data when it is deleted
+    #[serde(default)]
+    pub delete_policy: DeletePolicy,
+    /// How the volume's data is destroyed before it is removed
+    #[serde(default)]
+    pub wipe_on_delete: WipeMode,
+    /// Bytes already wiped, while in [`VolumeState::Wiping`]
+    #[serde(default)]
+    pub wipe_offset: u64,
+    /// Filesystem check run before the volume is mounted
+    #[serde(default)]
+    pub fsck: FsckPolicy,
+    /// Last known health of the volume, reported as its `VolumeCondition`
+    #[serde(default)]
+    pub condition: Option<Condition>,
+    /// Image grown while unpublished, its filesystem is grown on the next publish
+    #[serde(default)]
+    pub needs_fs_resize: bool,
+    /// Shrink the unpublished volume to the size requested by its PV's `lvp/shrink-to` annotation
+    #[serde(default)]
+    pub allow_shrink: bool,
+    /// Size requested through `lvp/shrink-to` that the data doesn't fit in, not retried until republished
+    #[serde(default)]
+    pub refused_shrink: Option<u64>,
+    /// Grows the volume through its PVC as it fills up
+    #[serde(default)]
+    pub autogrow: Option<Autogrow>,
+    /// Attach the loop device with direct I/O, bypassing the host's page cache
+    #[serde(default)]
+    pub direct_io: bool,
+    /// Logical sector size of the loop device, 512 bytes if unset
+    #[serde(default)]
+    pub logical_block_size: Option<u32>,
+    /// cgroup `io.max` limits on the volume's loop device, applied to the pods it is published to
+    #[serde(default)]
+    pub io_limits: Option<IoLimits>,
+    /// `major:minor` of the loop device the `io_limits` were last applied to
+    #[serde(default)]
+    pub throttled_device: Option<String>,
+    /// Owner of the volume's root directory when it is made
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// Group of the volume's root directory when it is made
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Permissions of the volume's root directory when it is made
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Adopted from a static PV rather than made by lvp, so its ownership is left alone
+    #[serde(default)]
+    pub adopted: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IoLimits {
+    #[serde(default)]
+    pub read_iops: Option<u64>,
+    #[serde(default)]
+    pub write_iops: Option<u64>,
+    /// Bytes per second
+    #[serde(default)]
+    pub read_bps: Option<u64>,
+    /// Bytes per second
+    #[serde(default)]
+    pub write_bps: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Autogrow {
+    /// Percentage of space in use that triggers growth
+    pub threshold: u8,
+    /// Bytes added each time
+    pub increment: u64,
+    /// Size the volume is never grown past
+    pub max_size: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Condition {
+    pub abnormal: bool,
+    pub message: String,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -37,6 +131,7 @@ pub enum Filesystem {
     Ext4,
     Xfs,
     Bind,
+    Tmpfs,
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
@@ -46,6 +141,42 @@ pub enum VolumeState {
     Open,
     ControllerPublished,
     NodePublished,
+    /// Being wiped for deletion, resumed by the next delete request if interrupted
+    Wiping,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletePolicy {
+    /// Remove the data from the host
+    #[default]
+    Delete,
+    /// Move the data into the pool's trash directory, purged after `archive_ttl`
+    Archive,
+    /// Leave the data in place, only dropping lvp's metadata
+    Retain,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WipeMode {
+    #[default]
+    None,
+    /// Punch out the whole image file, or `BLKDISCARD` block devices
+    Discard,
+    /// Overwrite with zeroes
+    Zero,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FsckPolicy {
+    #[default]
+    None,
+    /// Refuse to mount a corrupt filesystem
+    Check,
+    /// Repair what can be repaired automatically, refusing to mount otherwise
+    Repair,
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -67,6 +198,24 @@ impl Volume {
         format!("lvp-vol-{}", self.name)
     }
 
+    /// Labels mapping the store object back to its claim, skipping values that can't be label values
+    fn labels(&self) -> BTreeMap<String, String> {
+        [
+            ("lvp/pvc-name", &self.pvc_name),
+            ("lvp/pvc-namespace", &self.pvc_namespace),
+            ("lvp/pv-name", &self.pv_name),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value.clone()?)))
+        .filter(|(_, value)| {
+            value.len() <= 63
+                && value
+                    .chars()
+                    .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_' || x == '.')
+        })
+        .collect()
+    }
+
     pub async fn create(&self) -> Result<VolumeCreation> {
         let serialized = serde_json::to_string(&self)?;
         let key = self.key();
@@ -81,6 +230,7 @@ impl Volume {
             metadata: ObjectMeta {
                 name: Some(key),
                 namespace: Some(NAMESPACE.clone()),
+                labels: Some(self.labels()),
                 ..Default::default()
             },
             ..Default::default()
@@ -100,6 +250,29 @@ impl Volume {
         Ok(())
     }
 
+    /// Changes a stored volume in place, replacing it only if nothing saved it since it was read so
+    /// concurrent updates aren't reverted. Returns false if the volume no longer exists.
+    pub async fn modify(name: &str, mut change: impl FnMut(&mut Self)) -> Result<bool> {
+        let configs: Api<ConfigMap> = Api::default_namespaced(CLIENT.clone());
+        let key = format!("lvp-vol-{name}");
+        loop {
+            let Some(mut config) = configs.get_opt(&key).await? else {
+                return Ok(false);
+            };
+            let data = config.data.get_or_insert_with(Default::default);
+            let mut volume: Self =
+                serde_json::from_str(data.get("data.json").context("missing data.json")?)?;
+            change(&mut volume);
+            data.insert("data.json".to_string(), serde_json::to_string(&volume)?);
+            // the resourceVersion read above makes the replace fail if someone else got there first
+            match configs.replace(&key, &Default::default(), &config).await {
+                Ok(_) => return Ok(true),
+                Err(kube::Error::Api(e)) if e.code == 409 => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
     pub async fn delete(&self) -> Result<()> {
         let configs: Api<ConfigMap> = Api::default_namespaced(CLIENT.clone());
         configs.delete(&self.key(), &Default::default()).await?;
@@ -0,0 +1,153 @@
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+use crate::{
+    config::CONFIG,
+    executor,
+    store::{self, IoLimits},
+};
+
+/// Pod UID in a kubelet target path, `/var/lib/kubelet/pods/<uid>/volumes/...`
+fn pod_uid(target: &Path) -> Option<&str> {
+    let mut components = target.components();
+    components.find(|x| x == &Component::Normal("pods".as_ref()))?;
+    components.next()?.as_os_str().to_str()
+}
+
+/// Whether a cgroup directory belongs to the pod, as named by the cgroupfs or systemd drivers
+fn is_pod_cgroup(name: &str, uid: &str) -> bool {
+    name == format!("pod{uid}") || name.ends_with(&format!("pod{}.slice", uid.replace('-', "_")))
+}
+
+/// Cgroups between the root and pods: `kubepods`, then the burstable and best effort QoS classes
+fn is_parent_cgroup(name: &str, depth: usize) -> bool {
+    match depth {
+        0 => name.starts_with("kubepods"),
+        1 => {
+            matches!(name, "burstable" | "besteffort")
+                || name.starts_with("kubepods-") && !name.contains("-pod")
+        }
+        _ => false,
+    }
+}
+
+/// The pod's cgroup on the host, found beneath the kubelet's `kubepods` hierarchy
+async fn pod_cgroup(uid: &str) -> Result<Option<PathBuf>> {
+    let mut pending = vec![CONFIG.cgroup_path.clone()];
+    for depth in 0..3 {
+        let mut next = vec![];
+        for dir in pending {
+            let mut entries = tokio::fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("failed to read cgroup '{}'", dir.display()))?;
+            while let Some(entry) = entries.next_entry().await? {
+                if !entry.file_type().await?.is_dir() {
+                    continue;
+                }
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if is_pod_cgroup(&name, uid) {
+                    return Ok(Some(entry.path()));
+                }
+                if is_parent_cgroup(&name, depth) {
+                    next.push(entry.path());
+                }
+            }
+        }
+        pending = next;
+    }
+    Ok(None)
+}
+
+/// `major:minor` of a loop device, as `io.max` keys its entries
+async fn device_number(loop_device: &Path) -> Result<String> {
+    let metadata = tokio::fs::metadata(executor::device_path(loop_device)).await?;
+    let rdev = metadata.rdev();
+    let (major, minor) = unsafe { (libc::major(rdev), libc::minor(rdev)) };
+    Ok(format!("{major}:{minor}"))
+}
+
+fn io_max_line(device: &str, limits: Option<&IoLimits>) -> String {
+    let limit = |x: Option<u64>| {
+        x.map(|x| x.to_string())
+            .unwrap_or_else(|| "max".to_string())
+    };
+    let limits = limits.cloned().unwrap_or_default();
+    format!(
+        "{device} rbps={} wbps={} riops={} wiops={}",
+        limit(limits.read_bps),
+        limit(limits.write_bps),
+        limit(limits.read_iops),
+        limit(limits.write_iops)
+    )
+}
+
+/// Writes an `io.max` entry into the cgroup of the pod a volume is published to, returning false if
+/// the pod has no cgroup
+async fn write_limits(target: &Path, device: &str, limits: Option<&IoLimits>) -> Result<bool> {
+    let uid = pod_uid(target).context("target path isn't within a pod")?;
+    let Some(cgroup) = pod_cgroup(uid).await? else {
+        return Ok(false);
+    };
+    tokio::fs::write(cgroup.join("io.max"), io_max_line(device, limits))
+        .await
+        .with_context(|| {
+            format!(
+                "failed to write io.max of '{}', is the io controller enabled?",
+                cgroup.display()
+            )
+        })?;
+    Ok(true)
+}
+
+async fn limit_pod(target: &Path, device: &str, limits: &IoLimits) -> Result<()> {
+    if !write_limits(target, device, Some(limits)).await? {
+        anyhow::bail!("no cgroup found for the pod of '{}'", target.display());
+    }
+    Ok(())
+}
+
+/// Throttles a volume's loop device for the pod at `target`. If the device was re-attached under a new
+/// number, the pods it is already published to are moved over to it as well.
+pub async fn apply(volume: &mut store::Volume, target: &Path) -> Result<()> {
+    let (Some(limits), Some(loop_device)) = (&volume.io_limits, &volume.loop_device) else {
+        return Ok(());
+    };
+    let device = device_number(loop_device).await?;
+    if volume.throttled_device.as_ref() != Some(&device) {
+        for path in &volume.mount_paths {
+            if let Some(old) = &volume.throttled_device {
+                if let Err(e) = write_limits(path, old, None).await {
+                    warn!(
+                        "failed to lift limits on stale device {old} of volume {}: {e:#}",
+                        volume.name
+                    );
+                }
+            }
+            limit_pod(path, &device, limits).await?;
+        }
+        if volume.throttled_device.is_some() {
+            info!(
+                "volume {} is now loop device {device}, moved its I/O limits",
+                volume.name
+            );
+        }
+        volume.throttled_device = Some(device.clone());
+    }
+    limit_pod(target, &device, limits).await
+}
+
+/// Lifts a volume's limits from the pod at `target`, before its device number can be reused
+pub async fn remove(volume: &store::Volume, target: &Path) -> Result<()> {
+    let (Some(_), Some(device)) = (&volume.io_limits, &volume.throttled_device) else {
+        return Ok(());
+    };
+    // a pod without a cgroup took its limits with it
+    write_limits(target, device, None).await?;
+    Ok(())
+}
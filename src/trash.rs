@@ -0,0 +1,78 @@
+use std::{
+    io::ErrorKind,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::{error, info};
+
+use crate::{
+    config::CONFIG,
+    paths::{VolumePath, TRASH_DIR},
+};
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Name of a volume's trash entry, prefixed with the unix time it was archived at
+pub fn archive_name(volume_name: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}-{volume_name}", now.as_secs())
+}
+
+fn archived_at(name: &str) -> Option<SystemTime> {
+    let (timestamp, _) = name.split_once('-')?;
+    Some(UNIX_EPOCH + Duration::from_secs(timestamp.parse().ok()?))
+}
+
+async fn purge_root(root: &Path, ttl: Duration) -> Result<()> {
+    let mut entries = match tokio::fs::read_dir(root.join(TRASH_DIR)).await {
+        Ok(x) => x,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let now = SystemTime::now();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // leave anything we didn't put there alone
+        let Some(archived_at) = archived_at(&name) else {
+            continue;
+        };
+        if now.duration_since(archived_at).unwrap_or_default() < ttl {
+            continue;
+        }
+        info!("purging archived volume '{name}' from {}", root.display());
+        // one stubborn entry shouldn't keep the rest around
+        let removed = match VolumePath::trash_entry(root, &name) {
+            Ok(x) => x.remove().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = removed {
+            error!("failed to purge '{name}' from {}: {e:#}", root.display());
+        }
+    }
+    Ok(())
+}
+
+/// Removes archived volumes older than `ttl` from every trash directory
+pub async fn purge(ttl: Duration) {
+    for root in VolumePath::roots() {
+        if let Err(e) = purge_root(&root, ttl).await {
+            error!("failed to purge trash in {}: {e:#}", root.display());
+        }
+    }
+}
+
+/// Periodically purges trash, if `archive_ttl` is configured
+pub async fn run_purger() {
+    let Some(ttl) = CONFIG.archive_ttl else {
+        return;
+    };
+    let ttl = Duration::from_secs(ttl);
+    loop {
+        purge(ttl).await;
+        tokio::time::sleep(PURGE_INTERVAL.min(ttl.max(Duration::from_secs(60)))).await;
+    }
+}
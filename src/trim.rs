@@ -0,0 +1,128 @@
+use std::{
+    fs::File,
+    io,
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::{error, info, warn};
+
+use crate::{
+    config::{CONFIG, NODE},
+    paths::VolumePath,
+    store::{self, Filesystem, VolumeState},
+};
+
+// linux/fs.h, _IOWR('X', 121, struct fstrim_range)
+const FITRIM: libc::c_ulong = 0xC0185879;
+
+#[repr(C)]
+struct FstrimRange {
+    start: u64,
+    len: u64,
+    minlen: u64,
+}
+
+/// Discards the unused blocks of a mounted filesystem, returning the bytes it trimmed
+async fn fitrim(mount_point: &Path) -> io::Result<u64> {
+    let file = tokio::fs::File::open(mount_point).await?.into_std().await;
+    tokio::task::spawn_blocking(move || {
+        let mut range = FstrimRange {
+            start: 0,
+            len: u64::MAX,
+            minlen: 0,
+        };
+        if unsafe { libc::ioctl(file.as_raw_fd(), FITRIM, &mut range) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(range.len)
+    })
+    .await?
+}
+
+/// Whether a loop device passes discards on by punching holes in its backing file
+async fn supports_discard(loop_device: &Path) -> bool {
+    let name = loop_device.file_name().unwrap_or_default();
+    let path = Path::new("/sys/block")
+        .join(name)
+        .join("queue/discard_max_bytes");
+    match tokio::fs::read_to_string(path).await {
+        Ok(x) => x.trim() != "0",
+        Err(_) => false,
+    }
+}
+
+/// Bytes of the image file actually allocated on the host
+async fn allocated_bytes(volume: &store::Volume) -> Option<u64> {
+    let file: File = VolumePath::of(volume).ok()?.open(libc::O_PATH).await.ok()?;
+    Some(file.metadata().ok()?.blocks() * 512)
+}
+
+/// Trims a mounted loop volume so space freed within it is given back to the host
+pub async fn trim_volume(volume: &store::Volume, mount_point: &Path) -> Result<()> {
+    if !matches!(volume.filesystem, Filesystem::Ext4 | Filesystem::Xfs) {
+        return Ok(());
+    }
+    let Some(loop_device) = &volume.loop_device else {
+        return Ok(());
+    };
+    if !supports_discard(loop_device).await {
+        warn!(
+            "loop device {} of volume {} doesn't support discard, not trimming",
+            loop_device.display(),
+            volume.name
+        );
+        return Ok(());
+    }
+    let before = allocated_bytes(volume).await;
+    let trimmed = match fitrim(mount_point).await {
+        Ok(x) => x,
+        // nothing gets freed on read-only filesystems anyway
+        Err(e) if e.raw_os_error() == Some(libc::EROFS) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let after = allocated_bytes(volume).await;
+    let reclaimed = match (before, after) {
+        (Some(before), Some(after)) => before.saturating_sub(after),
+        _ => 0,
+    };
+    info!(
+        "trimmed volume {}: {trimmed} bytes discarded, {reclaimed} bytes reclaimed on the host",
+        volume.name
+    );
+    Ok(())
+}
+
+async fn trim_pass() -> Result<()> {
+    for volume in store::Volume::list().await? {
+        if volume.assigned_node_id.as_deref() != Some(&**NODE)
+            || volume.state != VolumeState::NodePublished
+        {
+            continue;
+        }
+        // every mount shares the filesystem
+        let Some(mount_point) = volume.mount_paths.first() else {
+            continue;
+        };
+        if let Err(e) = trim_volume(&volume, mount_point).await {
+            error!("failed to trim volume {}: {e:#}", volume.name);
+        }
+    }
+    Ok(())
+}
+
+/// Periodically trims mounted loop volumes on this node
+pub async fn run() {
+    if CONFIG.trim.interval == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(CONFIG.trim.interval);
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = trim_pass().await {
+            error!("trimming volumes failed: {e:#}");
+        }
+    }
+}
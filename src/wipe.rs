@@ -0,0 +1,117 @@
+use std::{
+    fs::File,
+    io::{Error, Result, Seek, SeekFrom},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, FileTypeExt},
+    },
+    sync::Arc,
+};
+
+use anyhow::Context;
+use log::info;
+
+use crate::{
+    paths::VolumePath,
+    store::{self, Filesystem, WipeMode},
+};
+
+// linux/fs.h, _IO(0x12, 119)
+const BLKDISCARD: libc::c_ulong = 0x1277;
+
+/// Bytes wiped between progress updates in the store
+const CHUNK_SIZE: u64 = 1 << 30;
+const ZERO_BUFFER_SIZE: usize = 1 << 20;
+
+fn check(ret: libc::c_int) -> Result<()> {
+    if ret < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn discard(file: &File, block_device: bool, offset: u64, len: u64) -> Result<()> {
+    if block_device {
+        let range = [offset, len];
+        check(unsafe { libc::ioctl(file.as_raw_fd(), BLKDISCARD, range.as_ptr()) })
+    } else {
+        check(unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        })
+    }
+}
+
+fn zero(file: &File, offset: u64, len: u64) -> Result<()> {
+    let buffer = vec![0u8; ZERO_BUFFER_SIZE];
+    let mut written = 0u64;
+    while written < len {
+        let size = (len - written).min(buffer.len() as u64) as usize;
+        file.write_all_at(&buffer[..size], offset + written)?;
+        written += size as u64;
+    }
+    file.sync_data()
+}
+
+fn wipe_range(
+    file: &File,
+    mode: WipeMode,
+    block_device: bool,
+    offset: u64,
+    len: u64,
+) -> Result<()> {
+    match mode {
+        WipeMode::None => Ok(()),
+        WipeMode::Discard => discard(file, block_device, offset, len),
+        WipeMode::Zero => zero(file, offset, len),
+    }
+}
+
+/// Wipes the data backing a volume according to its `wipe_on_delete` mode, saving progress to the store
+/// so an interrupted wipe resumes from where it left off
+pub async fn wipe_volume_data(volume: &mut store::Volume) -> anyhow::Result<()> {
+    // tmpfs contents are gone once unmounted
+    if volume.wipe_on_delete == WipeMode::None || volume.filesystem == Filesystem::Tmpfs {
+        return Ok(());
+    }
+    let volume_path = VolumePath::of(volume)?;
+    let file = match volume_path.open(libc::O_WRONLY).await {
+        Ok(x) => x,
+        // never created, volumes are only made on first publish
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context("failed to open volume for wiping"),
+    };
+    let block_device = file.metadata()?.file_type().is_block_device();
+    let mut file = file;
+    let size = file.seek(SeekFrom::End(0))?;
+    let file = Arc::new(file);
+
+    if volume.wipe_offset < size {
+        info!(
+            "wiping volume {} ({:?}) from offset {} of {size}",
+            volume.name, volume.wipe_on_delete, volume.wipe_offset
+        );
+    }
+    while volume.wipe_offset < size {
+        let offset = volume.wipe_offset;
+        let len = CHUNK_SIZE.min(size - offset);
+        let mode = volume.wipe_on_delete;
+        let chunk_file = file.clone();
+        tokio::task::spawn_blocking(move || {
+            wipe_range(&chunk_file, mode, block_device, offset, len)
+        })
+        .await?
+        .with_context(|| format!("failed to wipe volume at offset {offset}"))?;
+        volume.wipe_offset = offset + len;
+        volume
+            .update()
+            .await
+            .context("failed to save wipe progress")?;
+    }
+    Ok(())
+}